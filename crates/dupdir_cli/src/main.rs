@@ -2,10 +2,25 @@ use dupdir_core::prelude::*;
 
 use core::str;
 use core::str::FromStr as _;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
 use indicatif::ProgressIterator as _;
+use std::collections::VecDeque;
 use std::env;
+use std::ffi::OsString;
 use std::error;
 use std::io;
+use std::path::PathBuf;
+use std::process;
+
+
+
+// =================
+// === Constants ===
+// =================
+
+/// Conventional exit code for a process terminated by `SIGINT`.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
 
 
@@ -16,6 +31,10 @@ use std::io;
 #[derive(Debug)]
 enum Command {
     All,
+    Contained,
+    Files,
+    Similar,
+    State,
 }
 
 // === Trait `impl`s ===
@@ -26,6 +45,10 @@ impl str::FromStr for Command {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let command = match s {
             "all" => Self::All,
+            "contained" => Self::Contained,
+            "files" => Self::Files,
+            "similar" => Self::Similar,
+            "state" => Self::State,
             _ => Err(format!("Invalid command: {s}"))?,
         };
         Ok(command)
@@ -34,23 +57,329 @@ impl str::FromStr for Command {
 
 
 
+// ====================
+// === StateCommand ===
+// ====================
+
+#[derive(Debug)]
+enum StateCommand {
+    Gc,
+    Migrate,
+    Stats,
+}
+
+// === Trait `impl`s ===
+
+impl str::FromStr for StateCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let command = match s {
+            "gc" => Self::Gc,
+            "migrate" => Self::Migrate,
+            "stats" => Self::Stats,
+            _ => Err(format!("Invalid state command: {s}"))?,
+        };
+        Ok(command)
+    }
+}
+
+
+
+// =================
+// === FilterArg ===
+// =================
+
+/// A command-line option adding to the scan's [`Filter`].
+#[derive(Debug)]
+enum FilterArg {
+    Exclude(String),
+    Include(String),
+    ExcludeFrom(PathBuf),
+}
+
+
+
+// ============
+// === Args ===
+// ============
+
+/// Command-line arguments, split into options (which may appear anywhere) and positional
+/// arguments.
+#[derive(Debug, Default)]
+struct Args {
+    /// Positional arguments, which may be paths and so need not be valid UTF-8.
+    positional: VecDeque<OsString>,
+    /// Explicit path of the state file (`--state <path>`).
+    state: Option<PathBuf>,
+    /// Named state profile (`--profile <name>`).
+    profile: Option<String>,
+    /// Backend used for the default state location (`--store json|sqlite`).
+    store: StoreKind,
+    /// How results are written to stdout (`--format text|quoted|nul`).
+    format: OutputFormat,
+    /// List the duplicate directories which are only hardlinked copies, rather than the actual
+    /// copies (`--hardlinks`).
+    hardlinks: bool,
+    /// Include and exclude patterns, in the order given (`--include <pattern>`,
+    /// `--exclude <pattern>` and `--exclude-from <path>`).
+    filter: Vec<FilterArg>,
+    /// Don't apply the user's exclude patterns from [`dupdir_core::ignore_file_path`]
+    /// (`--no-ignore-file`).
+    no_ignore_file: bool,
+    /// Options passed to the scan.
+    options: Options,
+}
+
+// === Internal `impl`s ===
+
+impl Args {
+    fn parse(args: impl Iterator<Item = OsString>) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Options are always UTF-8, so anything else is a positional argument.
+            let Some(arg_str) = arg.to_str() else {
+                parsed.positional.push_back(arg);
+                continue;
+            };
+            let (option, value) = match arg_str.split_once('=') {
+                Some((option, value)) if option.starts_with("--") => (option, Some(value)),
+                _ => (arg_str, None),
+            };
+            let mut value_os = || match value {
+                Some(value) => Ok(OsString::from(value)),
+                None => args.next().ok_or(format!("Missing value for option: {option}")),
+            };
+            let mut value = || {
+                let value = value_os()?;
+                let value = value.into_string();
+                value.map_err(|v| format!("Invalid value for option {option}: {v:?}"))
+            };
+            match option {
+                "--state" => parsed.state = Some(value_os()?.into()),
+                "--profile" => parsed.profile = Some(profile_arg(value()?)?),
+                "--store" => parsed.store = StoreKind::from_str(&value()?)?,
+                "--format" => parsed.format = OutputFormat::from_str(&value()?)?,
+                "-0" => parsed.format = OutputFormat::Nul,
+                "--hardlinks" => parsed.hardlinks = true,
+                "--algorithm" => parsed.options.algorithm = Algorithm::from_str(&value()?)?,
+                "--on-algorithm-mismatch" => {
+                    let policy = AlgorithmMismatch::from_str(&value()?)?;
+                    parsed.options.on_algorithm_mismatch = policy;
+                }
+                "--on-file-error" => {
+                    let policy = ErrorPolicy::from_str(&value()?)?;
+                    parsed.options.on_file_error = policy;
+                }
+                "--symlinks" => {
+                    let policy = SymlinkPolicy::from_str(&value()?)?;
+                    parsed.options.symlinks = policy;
+                }
+                "--follow-symlinks" => parsed.options.symlinks = SymlinkPolicy::Follow,
+                "--one-file-system" => parsed.options.one_file_system = true,
+                "--log-skipped" => parsed.options.log_skipped = true,
+                "--exclude" => parsed.filter.push(FilterArg::Exclude(value()?)),
+                "--include" => parsed.filter.push(FilterArg::Include(value()?)),
+                "--exclude-from" => parsed.filter.push(FilterArg::ExcludeFrom(value_os()?.into())),
+                "--no-ignore-file" => parsed.no_ignore_file = true,
+                "--gitignore" => parsed.options.filter.gitignore = true,
+                "--dir-hash" => parsed.options.dir_hash_mode = DirHashMode::from_str(&value()?)?,
+                "--exclude-root" => parsed.options.exclude_root = true,
+                "--expanded" => parsed.options.expanded = true,
+                "--similarity" => parsed.options.similarity = Similarity::from_str(&value()?)?,
+                "--min-similarity" => {
+                    parsed.options.min_similarity = min_similarity_arg(&value()?)?;
+                }
+                "--full-hash" => parsed.options.staged = false,
+                "--verify" => parsed.options.verify = true,
+                "--junk-files" => parsed.options.junk_files = junk_files_arg(&value()?),
+                "--checkpoint-interval" => {
+                    let interval = checkpoint_interval_arg(&value()?)?;
+                    parsed.options.checkpoint_interval = interval;
+                }
+                "--" => {
+                    parsed.positional.extend(args);
+                    break;
+                }
+                _ if option.starts_with("--") => Err(format!("Unknown option: {option}"))?,
+                _ => parsed.positional.push_back(arg),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn next(&mut self) -> Option<OsString> {
+        self.positional.pop_front()
+    }
+
+    /// Returns the next positional argument, which must be valid UTF-8.
+    fn next_str(&mut self) -> Result<Option<String>, String> {
+        let arg = self.next().map(OsString::into_string).transpose();
+        arg.map_err(|a| format!("Invalid argument: {a:?}"))
+    }
+
+    /// Builds the scan's filter from the user's ignore file followed by the patterns given on the
+    /// command line, so that the latter take precedence.
+    fn filter(&self) -> Result<Filter, dupdir_core::Error> {
+        let mut filter = self.options.filter.clone();
+        let ignore_file = dupdir_core::ignore_file_path().filter(|p| p.is_file());
+        if let Some(path) = ignore_file.filter(|_| !self.no_ignore_file) {
+            filter.exclude_from(&path)?;
+        }
+        self.filter.iter().try_for_each(|arg| match arg {
+            FilterArg::Exclude(pattern) => filter.exclude(pattern),
+            FilterArg::Include(pattern) => filter.include(pattern),
+            FilterArg::ExcludeFrom(path) => filter.exclude_from(path),
+        })?;
+        Ok(filter)
+    }
+
+    fn state_path(&self) -> Result<PathBuf, &'static str> {
+        match (&self.state, &self.profile) {
+            (Some(_), Some(_)) => Err("Options --state and --profile are mutually exclusive."),
+            (Some(path), None) => Ok(path.clone()),
+            (None, profile) => Ok(dupdir_core::state_path(profile.as_deref(), self.store)),
+        }
+    }
+}
+
+/// Parses a checkpoint interval in seconds, where `0` disables periodic checkpoints.
+fn checkpoint_interval_arg(interval: &str) -> Result<Option<Duration>, String> {
+    let seconds = u64::from_str(interval);
+    let seconds = seconds.map_err(|_| format!("Invalid checkpoint interval: {interval:?}"))?;
+    let interval = match seconds {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    };
+    Ok(interval)
+}
+
+/// Parses a comma-separated list of junk file names, where an empty list disables the defaults.
+fn junk_files_arg(names: &str) -> Vec<String> {
+    let names = names.split(',').filter(|n| !n.is_empty());
+    names.map(str::to_string).collect()
+}
+
+/// Parses a similarity between `0` and `1`.
+fn min_similarity_arg(similarity: &str) -> Result<f64, String> {
+    let parsed = f64::from_str(similarity).ok().filter(|s| (0.0..=1.0).contains(s));
+    parsed.ok_or(format!("Invalid similarity: {similarity:?}"))
+}
+
+fn profile_arg(profile: String) -> Result<String, String> {
+    let valid = !profile.is_empty()
+        && profile != "."
+        && profile != ".."
+        && !profile.contains(['/', '\\']);
+    match valid {
+        true => Ok(profile),
+        false => Err(format!("Invalid profile name: {profile:?}")),
+    }
+}
+
+
+
 // ============
 // === Main ===
 // ============
 
 fn main() {
     fn main() -> Result<(), Box<dyn error::Error>> {
-        let mut args = env::args();
-        let _command = args.next();
-        let command = args.next().expect("Command required");
+        let args = env::args_os();
+        let args = args.skip(1);
+        let mut args = Args::parse(args)?;
+        let command = args.next_str()?.ok_or("Command required.")?;
         let command = Command::from_str(&command)?;
+        let state_path = args.state_path()?;
+        args.options.filter = args.filter()?;
+        handle_interrupts(&args.options)?;
         match command {
             Command::All => {
                 let search_path = path_arg(&mut args)?;
-                let mut state = State::load(STATE_JSON);
-                let lines = dupdir_core::run_all(&mut state, &search_path);
+                let mut state = State::load(&state_path)?;
+                let report = dupdir_core::run_all(&mut state, &search_path, &args.options)?;
+                report_errors(&report.scan);
+                report_collisions(report.collisions);
+                let dup_dirs = report.dup_dirs.into_iter();
+                let (hardlinked, copies) = dup_dirs.partition::<Vec<_>, _>(|d| d.hardlinked);
+                let dup_dirs = match args.hardlinks {
+                    true => hardlinked,
+                    false => {
+                        report_hardlinks(hardlinked.len(), "directories");
+                        copies
+                    },
+                };
+
+                // Write the resulting records to stdout.
+                let mut writer = stdout_writer();
+                write_dup_dirs(&mut writer, args.format, &dup_dirs)?;
+            }
+            Command::Files => {
+                let search_path = path_arg(&mut args)?;
+                let mut state = State::load(&state_path)?;
+                let report = dupdir_core::scan(&mut state, &search_path, &args.options)?;
+                report_errors(&report);
+                let verified = dupdir_core::verify_files(&state, &search_path, &args.options)?;
+                report_verified(&verified);
+                let options = &args.options;
+                let dup_files = dupdir_core::dup_files(&state, &search_path, &verified, options);
+                let dup_files = dup_files.into_iter();
+                let (hardlinked, copies) = dup_files.partition::<Vec<_>, _>(|f| f.hardlinked);
+                let dup_files = match args.hardlinks {
+                    true => hardlinked,
+                    false => {
+                        report_hardlinks(hardlinked.len(), "files");
+                        copies
+                    },
+                };
+
+                let mut writer = stdout_writer();
+                write_dup_files(&mut writer, args.format, &dup_files)?;
+            }
+            Command::Contained => {
+                let search_path = path_arg(&mut args)?;
+                let mut state = State::load(&state_path)?;
+                let report = dupdir_core::scan(&mut state, &search_path, &args.options)?;
+                report_errors(&report);
+                let contained = dupdir_core::contained_dirs(&state, &search_path, &args.options);
+
+                let mut writer = stdout_writer();
+                write_contained_dirs(&mut writer, args.format, &contained)?;
+            }
+            Command::Similar => {
+                let search_path = path_arg(&mut args)?;
+                let mut state = State::load(&state_path)?;
+                let report = dupdir_core::scan(&mut state, &search_path, &args.options)?;
+                report_errors(&report);
+                let similar_dirs = dupdir_core::similar_dirs(&state, &search_path, &args.options);
+
+                let mut writer = stdout_writer();
+                write_similar_dirs(&mut writer, args.format, &similar_dirs)?;
+            }
+            Command::State => {
+                let command = args.next_str()?.ok_or("State command required.")?;
+                let command = StateCommand::from_str(&command)?;
+                let lines = match command {
+                    StateCommand::Gc => {
+                        let mut state = State::load(&state_path)?;
+                        let report = state.gc(&args.options)?;
+                        state.save()?;
+                        report_errors(&report);
+                        stats_lines(report.stats)
+                    }
+                    StateCommand::Migrate => {
+                        // Migrates `<from>` into the selected state file.
+                        let from = path_arg(&mut args)?;
+                        let state = dupdir_core::store::migrate(&from, &state_path)?;
+                        state_stats_lines(&state)
+                    }
+                    StateCommand::Stats => {
+                        let state = State::load(&state_path)?;
+                        state_stats_lines(&state)
+                    }
+                };
 
-                // Write the resulting strings to stdout.
                 let mut writer = stdout_writer();
                 write_output(&mut writer, lines)?;
             }
@@ -60,12 +389,89 @@ fn main() {
 
     if let Err(e) = main() {
         eprintln!("Error: {e}");
+        let code = match e.downcast_ref::<Error>() {
+            Some(Error::Interrupted) => INTERRUPTED_EXIT_CODE,
+            _ => 1,
+        };
+        process::exit(code);
     }
 }
 
-fn path_arg(args: &mut env::Args) -> Result<String, &'static str> {
+/// Installs a Ctrl-C handler which asks the scan to stop and save its progress.
+///
+/// A second Ctrl-C exits immediately, without waiting for in-flight files to finish hashing.
+fn handle_interrupts(options: &Options) -> Result<(), ctrlc::Error> {
+    let interrupt = options.interrupt.clone();
+    ctrlc::set_handler(move || {
+        if interrupt.swap(true, Ordering::SeqCst) {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        eprintln!("Interrupted, saving progress (press Ctrl-C again to exit immediately)...");
+    })
+}
+
+fn path_arg(args: &mut Args) -> Result<PathBuf, &'static str> {
     let arg = args.next();
-    arg.ok_or("Path not provided.")
+    let arg = arg.ok_or("Path not provided.")?;
+    Ok(arg.into())
+}
+
+/// Prints the files which were skipped under `--on-file-error collect`.
+fn report_errors(report: &ScanReport) {
+    report.errors.iter().for_each(|e| eprintln!("Skipped: {e}"));
+    if !report.errors.is_empty() {
+        eprintln!("Skipped {} files or directories.", report.errors.len());
+    }
+    if report.skipped.total() > 0 {
+        eprintln!("Skipped special files ({}).", report.skipped);
+    }
+}
+
+/// Prints the number of groups split under `--verify`.
+fn report_collisions(count: usize) {
+    if count > 0 {
+        eprintln!("Split {count} groups whose hashes collided despite different contents.");
+    }
+}
+
+/// Prints the files which couldn't be verified under `--on-file-error collect`, and the groups
+/// split under `--verify`.
+fn report_verified(verified: &Verified) {
+    verified.errors.iter().for_each(|e| eprintln!("Unverified: {e}"));
+    if !verified.errors.is_empty() {
+        eprintln!("Could not verify {} files.", verified.errors.len());
+    }
+    report_collisions(verified.collisions);
+}
+
+fn report_hardlinks(count: usize, what: &str) {
+    if count > 0 {
+        eprintln!("Omitted {count} hardlinked {what}. List them with --hardlinks.");
+    }
+}
+
+fn stats_lines(stats: Stats) -> Vec<String> {
+    let Stats { dropped, kept, refreshed } = stats;
+    vec![
+        format!("dropped: {dropped}"),
+        format!("kept: {kept}"),
+        format!("refreshed: {refreshed}"),
+    ]
+}
+
+fn state_stats_lines(state: &State) -> Vec<String> {
+    let algorithm = state.algorithm().unwrap_or("none");
+    let mut lines = vec![
+        format!("algorithm: {algorithm}"),
+        format!("entries: {}", state.entries().len()),
+    ];
+    state.roots().for_each(|(path, root)| {
+        let path = escape_path(path);
+        let files = root.files().len();
+        let last_scan = root.last_scan();
+        lines.push(format!("root: {path} (files: {files}, last scan: {last_scan})"));
+    });
+    lines
 }
 
 fn stdout_writer() -> io::StdoutLock<'static> {
@@ -73,6 +479,76 @@ fn stdout_writer() -> io::StdoutLock<'static> {
     stdout.lock()
 }
 
+fn write_dup_dirs(
+    writer: &mut dyn io::Write,
+    format: OutputFormat,
+    dup_dirs: &[DupDir],
+) -> Result<(), io::Error> {
+    let dup_dirs = dup_dirs.iter();
+    let dup_dirs = dup_dirs.progress();
+    let dup_dirs = dup_dirs.map(|d| format.write_record(writer, &d.hash, &d.path));
+    dup_dirs.collect::<Result<(), _>>()
+}
+
+/// Writes each duplicate file as a record whose hash field also holds the size of the file and
+/// the bytes wasted by its copies, as `<hash>,<size>,<wasted>`.
+fn write_dup_files(
+    writer: &mut dyn io::Write,
+    format: OutputFormat,
+    dup_files: &[DupFile],
+) -> Result<(), io::Error> {
+    let dup_files = dup_files.iter();
+    let dup_files = dup_files.progress();
+    let dup_files = dup_files.map(|f| {
+        let fields = format!("{},{},{}", f.hash, f.size, f.wasted);
+        format.write_record(writer, &fields, &f.path)
+    });
+    dup_files.collect::<Result<(), _>>()
+}
+
+/// Writes each contained directory as a `contained` record, or `nearly-contained` if some of its
+/// files are missing from its containers, followed by an `in` record per container and a
+/// `missing` record per missing file.
+fn write_contained_dirs(
+    writer: &mut dyn io::Write,
+    format: OutputFormat,
+    contained: &[ContainedDir],
+) -> Result<(), io::Error> {
+    let contained = contained.iter();
+    let contained = contained.progress();
+    let contained = contained.map(|d| {
+        let kind = if d.missing.is_empty() { "contained" } else { "nearly-contained" };
+        let containers = d.containers.iter().map(|c| ("in", c));
+        let missing = d.missing.iter().map(|m| ("missing", m));
+        let mut records = [(kind, &d.path)].into_iter().chain(containers).chain(missing);
+        records.try_for_each(|(tag, path)| format.write_record(writer, tag, path))
+    });
+    contained.collect::<Result<(), _>>()
+}
+
+/// Writes each pair of similar directories as two consecutive records, one per directory. The
+/// hash field of each holds the similarity, the shared contents and the contents only found in
+/// that directory, as `<similarity>,<shared files>,<shared bytes>,<own files>,<own bytes>`.
+fn write_similar_dirs(
+    writer: &mut dyn io::Write,
+    format: OutputFormat,
+    similar_dirs: &[SimilarDirs],
+) -> Result<(), io::Error> {
+    let similar_dirs = similar_dirs.iter();
+    let similar_dirs = similar_dirs.progress();
+    let similar_dirs = similar_dirs.map(|d| {
+        let SimilarDirs { similarity, shared, .. } = d;
+        let sides = [(&d.left, d.left_only), (&d.right, d.right_only)];
+        sides.into_iter().try_for_each(|(path, own)| {
+            let shared = format!("{},{}", shared.files, shared.bytes);
+            let own = format!("{},{}", own.files, own.bytes);
+            let fields = format!("{similarity:.3},{shared},{own}");
+            format.write_record(writer, &fields, path)
+        })
+    });
+    similar_dirs.collect::<Result<(), _>>()
+}
+
 fn write_output(writer: &mut dyn io::Write, strings: Vec<String>) -> Result<(), io::Error> {
     let strings = strings.iter();
     let strings = strings.progress();
@@ -0,0 +1,165 @@
+use crate::prelude::*;
+
+use core::fmt;
+use core::str;
+use std::error;
+use std::io;
+
+
+
+// ==============
+// === Result ===
+// ==============
+
+pub type Result<T, E = Error> = core::result::Result<T, E>;
+
+
+
+// =============
+// === Error ===
+// =============
+
+#[derive(Debug)]
+#[must_use]
+pub enum Error {
+    /// The scan was stopped early through [`Options::interrupt`](crate::Options::interrupt).
+    Interrupted,
+    /// The state was hashed with a different algorithm than the one requested.
+    AlgorithmMismatch { stored: String, requested: String },
+    /// A file or directory could not be read.
+    Io { path: PathBuf, source: io::Error },
+    /// A directory entry could not be read while searching for files.
+    Walk(walkdir::Error),
+    /// A path does not satisfy the rules in [`check_path_rules`](crate::check_path_rules).
+    InvalidPath { path: PathBuf, reason: &'static str },
+    /// A JSON state file could not be parsed or written.
+    Json { path: PathBuf, source: serde_json::Error },
+    /// A SQLite state database could not be read or written.
+    #[cfg(feature = "sqlite")]
+    Sqlite { path: PathBuf, source: rusqlite::Error },
+    /// A state file is malformed.
+    InvalidState { path: PathBuf, reason: &'static str },
+    /// A state file was written by a newer, incompatible version.
+    UnsupportedVersion { path: PathBuf, version: u32, supported: u32 },
+    /// An include or exclude pattern of a [`Filter`](crate::Filter) is not a valid glob.
+    InvalidPattern(ignore::Error),
+}
+
+// === Main `impl` ===
+
+impl Error {
+    pub(crate) fn io(path: impl AsRef<Path>) -> impl FnOnce(io::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        |source| Self::Io { path, source }
+    }
+
+    pub(crate) fn json(path: impl AsRef<Path>) -> impl FnOnce(serde_json::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        |source| Self::Json { path, source }
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub(crate) fn sqlite(path: impl AsRef<Path>) -> impl FnOnce(rusqlite::Error) -> Self {
+        let path = path.as_ref().to_path_buf();
+        |source| Self::Sqlite { path, source }
+    }
+
+    /// Whether this error concerns a single file or directory, as opposed to the scan as a whole.
+    ///
+    /// Only these errors are collected under [`ErrorPolicy::Collect`].
+    pub fn is_per_file(&self) -> bool {
+        matches!(self, Self::Io { .. } | Self::Walk(_) | Self::InvalidPath { .. })
+    }
+}
+
+// === Trait `impl`s ===
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Interrupted => write!(f, "Interrupted; progress so far has been saved"),
+            Self::AlgorithmMismatch { stored, requested } => write!(
+                f,
+                "State was hashed with {stored}, but {requested} was requested; use \
+                 --on-algorithm-mismatch rehash to rehash all files"
+            ),
+            Self::Io { path, source } => write!(f, "{}: {source}", escape_path(path)),
+            Self::Walk(source) => write!(f, "{source}"),
+            Self::InvalidPath { path, reason } => write!(f, "{}: {reason}", escape_path(path)),
+            Self::Json { path, source } => write!(f, "{}: {source}", escape_path(path)),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite { path, source } => write!(f, "{}: {source}", escape_path(path)),
+            Self::InvalidState { path, reason } => write!(f, "{}: {reason}", escape_path(path)),
+            Self::UnsupportedVersion { path, version, supported } => write!(
+                f,
+                "{}: State has version {version}, but at most {supported} is supported; it was \
+                 written by a newer version of dupdir",
+                escape_path(path)
+            ),
+            Self::InvalidPattern(source) => write!(f, "Invalid pattern: {source}"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Walk(source) => Some(source),
+            Self::Json { source, .. } => Some(source),
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite { source, .. } => Some(source),
+            Self::InvalidPattern(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<walkdir::Error> for Error {
+    fn from(error: walkdir::Error) -> Self {
+        Self::Walk(error)
+    }
+}
+
+
+
+// ===================
+// === ErrorPolicy ===
+// ===================
+
+/// What to do when a single file or directory can't be read during a scan.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ErrorPolicy {
+    /// Stop the scan and return the error.
+    #[default]
+    Abort,
+    /// Skip the file, record the error in the [`ScanReport`](crate::ScanReport) and continue.
+    Collect,
+}
+
+// === Main `impl` ===
+
+impl ErrorPolicy {
+    /// Returns per-file errors to be collected, or the error itself if the scan should stop.
+    pub(crate) fn handle(self, error: Error) -> Result<Error> {
+        match self {
+            Self::Collect if error.is_per_file() => Ok(error),
+            _ => Err(error),
+        }
+    }
+}
+
+// === Trait `impl`s ===
+
+impl str::FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let policy = match s {
+            "abort" => Self::Abort,
+            "collect" => Self::Collect,
+            _ => Err(format!("Invalid error policy: {s}"))?,
+        };
+        Ok(policy)
+    }
+}
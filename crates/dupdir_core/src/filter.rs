@@ -0,0 +1,197 @@
+use crate::prelude::*;
+
+use ignore::gitignore::Gitignore;
+use ignore::gitignore::GitignoreBuilder;
+use ignore::Match;
+use std::fs;
+use std::sync::OnceLock;
+
+
+
+// =================
+// === Constants ===
+// =================
+
+/// Files in the scanned tree whose patterns apply under [`Filter::gitignore`], lowest precedence
+/// first.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+/// Directory skipped under [`Filter::gitignore`], as git itself never tracks it.
+const GIT_DIR: &str = ".git";
+
+
+
+// ==============
+// === Filter ===
+// ==============
+
+/// Gitignore-style rules deciding which files and directories a scan visits.
+///
+/// Patterns use the syntax of `.gitignore` files and are matched against paths relative to the
+/// search root: `target/` matches any directory named `target`, `/build` only the one directly
+/// under the root, and a later `!pattern` re-includes what an earlier pattern excluded. Excluded
+/// directories are not descended into, so they cost nothing to skip.
+#[derive(Clone, Debug, Default)]
+#[must_use]
+pub struct Filter {
+    excludes: Patterns,
+    includes: Patterns,
+    /// Also apply the `.gitignore` and `.ignore` files found in the scanned tree, and skip `.git`
+    /// directories. Explicit excludes and includes take precedence over these files.
+    pub gitignore: bool,
+}
+
+// === Main `impl` ===
+
+impl Filter {
+    /// Leaves the files and directories matching `pattern` out of the scan.
+    pub fn exclude(&mut self, pattern: &str) -> Result<()> {
+        self.excludes.add_line(None, pattern)
+    }
+
+    /// Restricts the scan to the files matching `pattern`, or any of the patterns given to
+    /// previous calls. Directories are still descended into unless excluded.
+    pub fn include(&mut self, pattern: &str) -> Result<()> {
+        self.includes.add_line(None, pattern)
+    }
+
+    /// Adds the exclude patterns of a file in `.gitignore` format, one per line.
+    pub fn exclude_from(&mut self, path: &Path) -> Result<()> {
+        let patterns = fs::read_to_string(path).map_err(Error::io(path))?;
+        let mut lines = patterns.lines();
+        lines.try_for_each(|line| self.excludes.add_line(Some(path.to_path_buf()), line))
+    }
+}
+
+
+
+// ================
+// === Patterns ===
+// ================
+
+/// Patterns matched against paths relative to the search root.
+#[derive(Clone, Debug)]
+struct Patterns {
+    builder: GitignoreBuilder,
+    /// Built from `builder` on first use, so that adding many lines costs a single build.
+    matcher: OnceLock<Gitignore>,
+}
+
+// === Main `impl` ===
+
+impl Patterns {
+    fn add_line(&mut self, from: Option<PathBuf>, line: &str) -> Result<()> {
+        self.builder.add_line(from, line).map_err(Error::InvalidPattern)?;
+        self.matcher = OnceLock::new();
+        Ok(())
+    }
+
+    fn matcher(&self) -> &Gitignore {
+        self.matcher.get_or_init(|| {
+            // Each line is validated as it is added, so only the combined set can fail to build.
+            self.builder.build().unwrap_or_else(|e| {
+                eprintln!("Skipping invalid patterns: {e}");
+                Gitignore::empty()
+            })
+        })
+    }
+}
+
+// === Trait `impl`s ===
+
+impl Default for Patterns {
+    fn default() -> Self {
+        // Rooted at `.` so that paths are matched as given, without stripping any prefix.
+        let builder = GitignoreBuilder::new(".");
+        let matcher = OnceLock::new();
+        Self { builder, matcher }
+    }
+}
+
+
+
+// ==================
+// === FilterWalk ===
+// ==================
+
+/// A [`Filter`] applied to the entries of a walk from `root`, in the order they are walked.
+#[derive(Debug)]
+pub(crate) struct FilterWalk {
+    filter: Filter,
+    root: PathBuf,
+    /// Matchers for the ignore files of the directories above the current entry, with the depth
+    /// of their directory, shallowest first.
+    ignore_files: Vec<(usize, Gitignore)>,
+}
+
+// === Main `impl` ===
+
+impl FilterWalk {
+    pub(crate) fn new(filter: Filter, root: PathBuf) -> Self {
+        Self { filter, root, ignore_files: Vec::new() }
+    }
+
+    pub(crate) fn with_filter(self, filter: Filter) -> Self {
+        Self { filter, ..self }
+    }
+
+    /// Whether to visit an entry, i.e. yield it if it is a file or descend into it if it is a
+    /// directory.
+    pub(crate) fn visit(&mut self, entry: &walkdir::DirEntry) -> bool {
+        let depth = entry.depth();
+        self.ignore_files.retain(|(d, _)| *d < depth);
+        let path = entry.path();
+        let is_dir = entry.file_type().is_dir();
+        if depth > 0 && !self.is_included(path, is_dir) {
+            return false;
+        }
+        if is_dir && self.filter.gitignore {
+            if depth > 0 && entry.file_name() == GIT_DIR {
+                return false;
+            }
+            self.load_ignore_files(path, depth);
+        }
+        true
+    }
+
+    fn is_included(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let excluded = match self.filter.excludes.matcher().matched(relative, is_dir) {
+            Match::Ignore(_) => true,
+            Match::Whitelist(_) => false,
+            Match::None => self.is_ignored_by_files(path, is_dir),
+        };
+        let includes = self.filter.includes.matcher();
+        let included = is_dir || includes.is_empty() || {
+            let matched = includes.matched_path_or_any_parents(relative, is_dir);
+            matched.is_ignore()
+        };
+        !excluded && included
+    }
+
+    /// Applies the ignore files of the enclosing directories, the deepest taking precedence.
+    fn is_ignored_by_files(&self, path: &Path, is_dir: bool) -> bool {
+        let ignore_files = self.ignore_files.iter().rev();
+        let mut matches = ignore_files.map(|(_, gitignore)| gitignore.matched(path, is_dir));
+        let matched = matches.find(|m| !m.is_none());
+        matched.is_some_and(|m| m.is_ignore())
+    }
+
+    fn load_ignore_files(&mut self, dir: &Path, depth: usize) {
+        let mut builder = GitignoreBuilder::new(dir);
+        let files = IGNORE_FILES.iter().map(|f| dir.join(f));
+        let files = files.filter(|f| f.is_file()).collect::<Vec<_>>();
+        if files.is_empty() {
+            return;
+        }
+        files.iter().for_each(|f| {
+            // Like git, invalid lines are reported but don't prevent the rest from applying.
+            if let Some(e) = builder.add(f) {
+                eprintln!("Skipping invalid ignore patterns: {e}");
+            }
+        });
+        match builder.build() {
+            Ok(gitignore) => self.ignore_files.push((depth, gitignore)),
+            Err(e) => eprintln!("Skipping invalid ignore patterns: {e}"),
+        }
+    }
+}
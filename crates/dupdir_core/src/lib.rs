@@ -1,7 +1,13 @@
 use crate::prelude::*;
 
 use core::fmt;
-use dupdir_hash::Hasher as _;
+use core::hash::Hash;
+use core::ops::Bound;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+use crate::filter::FilterWalk;
+use indicatif::ProgressBar;
 use indicatif::ProgressIterator as _;
 #[cfg(feature = "rayon")]
 use indicatif::ParallelProgressIterator as _;
@@ -11,8 +17,16 @@ use rayon::iter::IntoParallelIterator as _;
 use rayon::iter::IntoParallelRefIterator as _;
 #[cfg(feature = "rayon")]
 use rayon::iter::ParallelIterator as _;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::env;
 use std::fs;
+use std::io;
+use std::io::Read as _;
+use std::io::Seek as _;
 use std::str;
+use std::sync::Arc;
+use std::time::Instant;
 
 
 
@@ -20,8 +34,26 @@ use std::str;
 // === Constants ===
 // =================
 
+/// The algorithm used to hash files, unless another is set in [`Options::algorithm`].
+pub const DEFAULT_ALGORITHM: Algorithm = Algorithm::T1ha2;
 pub const STATE_JSON: &str = "state.json";
-const UNIQUE_SEPARATOR: &str = ";";
+pub const STATE_SQLITE: &str = "state.sqlite";
+/// Files created by file managers, which don't make two directories different by default.
+pub const DEFAULT_JUNK_FILES: [&str; 3] = [".DS_Store", "Thumbs.db", "desktop.ini"];
+const STATE_DIR: &str = "dupdir";
+const IGNORE_FILE: &str = "ignore";
+const DEFAULT_CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of files hashed between checks of the checkpoint interval and the interrupt flag.
+const CHECKPOINT_CHUNK_SIZE: usize = 1024;
+/// Prepended to the target of a recorded symlink before hashing it.
+const SYMLINK_HASH_PREFIX: &[u8] = b"dupdir:symlink\0";
+/// Prepended to the size of a file at [`Stage::Size`] before hashing it.
+const SIZE_HASH_PREFIX: &[u8] = b"dupdir:size\0";
+/// Prepended to the sample of a file at [`Stage::Sample`] before hashing it.
+const SAMPLE_HASH_PREFIX: &[u8] = b"dupdir:sample\0";
+/// Number of bytes read from the start and from the end of a file to sample it.
+const SAMPLE_SIZE: u64 = 4096;
+const DEFAULT_MIN_SIMILARITY: f64 = 0.9;
 
 
 
@@ -34,24 +66,94 @@ pub mod prelude {
     pub(crate) use serde::Serialize;
     pub(crate) use core::fmt::Debug;
     pub(crate) use core::fmt::Formatter;
-    pub(crate) use core::marker::PhantomData;
     pub(crate) use std::collections::BTreeMap;
     pub(crate) use std::collections::BTreeSet;
     pub(crate) use std::collections::HashMap;
     pub(crate) use std::path::Path;
     pub(crate) use std::path::PathBuf;
     pub(crate) use walkdir::WalkDir;
-    pub use crate::assert_path_rules;
+    pub use crate::check_path_rules;
+    pub use crate::escape_path;
+    pub use crate::quote_path;
+    pub use crate::path_from_bytes;
+    pub use crate::path_to_bytes;
     pub use crate::path_to_str;
     pub use crate::path_to_string;
+    pub use crate::ignore_file_path;
+    pub use crate::state_path;
+    pub use crate::ContainedDir;
+    pub use crate::DirHashMode;
+    pub use crate::DupDir;
+    pub use crate::DupFile;
+    pub use crate::Entry;
+    pub use crate::Algorithm;
+    pub use crate::AlgorithmMismatch;
+    pub use crate::Error;
+    pub use crate::ErrorPolicy;
+    pub use crate::FileKind;
+    pub use crate::Filter;
+    pub use crate::Result;
     pub use crate::FinderIter;
+    pub use crate::Metadata;
+    pub use crate::Options;
+    pub use crate::OutputFormat;
+    pub use crate::Report;
+    pub use crate::ScanReport;
+    pub use crate::Root;
+    pub use crate::SimilarDirs;
+    pub use crate::Stage;
+    pub use crate::Similarity;
+    pub use crate::Skipped;
     pub use crate::State;
+    pub use crate::Stats;
+    pub use crate::Verified;
+    pub use crate::store::StateStore;
+    pub use crate::store::StoreKind;
+    pub use crate::SymlinkPolicy;
     pub use crate::WalkDirFinder;
+    pub use crate::DEFAULT_ALGORITHM;
     pub use crate::STATE_JSON;
+    pub use crate::STATE_SQLITE;
 }
 
 
 
+// ==============
+// === Export ===
+// ==============
+
+pub mod error;
+pub mod filter;
+pub mod output;
+pub mod paths;
+pub mod similar;
+pub mod store;
+pub mod verify;
+
+pub use dupdir_hash::Algorithm;
+pub use error::Error;
+pub use error::ErrorPolicy;
+pub use error::Result;
+pub use filter::Filter;
+pub use output::OutputFormat;
+pub use paths::check_path_rules;
+pub use paths::escape_path;
+pub use paths::path_from_bytes;
+pub use paths::path_to_bytes;
+pub use paths::path_to_str;
+pub use paths::path_to_string;
+pub use paths::quote_path;
+pub use similar::contained_dirs;
+pub use similar::similar_dirs;
+pub use similar::ContainedDir;
+pub use similar::SimilarDirs;
+pub use similar::Similarity;
+pub use verify::verify_dirs;
+pub use verify::verify_files;
+pub use verify::Verified;
+
+
+
 // =============
 // === State ===
 // =============
@@ -59,49 +161,434 @@ pub mod prelude {
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 #[must_use]
 pub struct State {
-    files: Vec<PathBuf>,
-    hashes: BTreeMap<PathBuf, String>,
+    /// Identifier of the `dupdir_hash` algorithm the entries were hashed with, or `None` if
+    /// nothing has been hashed yet.
+    #[serde(default)]
+    algorithm: Option<String>,
+    /// The search roots that have been scanned, keyed by the path they were scanned from.
+    #[serde(default, with = "paths::map")]
+    roots: BTreeMap<PathBuf, Root>,
+    #[serde(with = "paths::map")]
+    hashes: BTreeMap<PathBuf, Entry>,
+    /// The file this state was loaded from, and will be saved to.
+    #[serde(skip)]
+    path: PathBuf,
+    /// Modifications made since the state was loaded or last saved, used by stores which support
+    /// incremental updates.
+    #[serde(skip)]
+    changes: Changes,
 }
 
 // === Main `impl` ===
 
 impl State {
-    pub fn save(&self) {
-        let json = serde_json::to_string_pretty(&self).expect("Serialize");
-        let path = Path::new(STATE_JSON);
-        fs::write(path, json).expect("Write");
+    /// Saves the state to the file it was loaded from, using the store matching its extension.
+    pub fn save(&mut self) -> Result<()> {
+        let mut store = store::open(self.path());
+        store.save(self)
     }
 
-    pub fn load(path: impl AsRef<Path>) -> Self {
+    /// Loads the state from `path`, using the store matching its extension.
+    ///
+    /// If the file does not exist, an empty state is returned.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
         let path = path.as_ref();
-        if path.exists() {
-            let json = fs::read_to_string(path).expect("Read");
-            serde_json::from_str(&json).expect("Deserialize")
-        } else {
-            Self::default()
+        let mut store = store::open(path);
+        let mut state = store.load()?;
+        state.path = path.to_path_buf();
+        Ok(state)
+    }
+
+    pub fn path(&self) -> &Path {
+        match self.path.as_os_str().is_empty() {
+            true => Path::new(STATE_JSON),
+            false => &self.path,
         }
     }
+
+    pub fn roots(&self) -> impl Iterator<Item = (&Path, &Root)> {
+        let roots = self.roots.iter();
+        roots.map(|(p, r)| (p.as_path(), r))
+    }
+
+    pub fn entries(&self) -> &BTreeMap<PathBuf, Entry> {
+        &self.hashes
+    }
+
+    pub fn algorithm(&self) -> Option<&str> {
+        self.algorithm.as_deref()
+    }
+
+    /// Ensures that the cached entries were hashed with the `requested` algorithm.
+    ///
+    /// If they were not, either refuses to continue or drops every entry so that all files are
+    /// rehashed, depending on `policy`.
+    pub fn check_algorithm(&mut self, requested: &str, policy: AlgorithmMismatch) -> Result<()> {
+        match &self.algorithm {
+            Some(stored) if stored == requested => return Ok(()),
+            Some(stored) if !self.hashes.is_empty() => match policy {
+                AlgorithmMismatch::Refuse => {
+                    let stored = stored.clone();
+                    let requested = requested.to_string();
+                    return Err(Error::AlgorithmMismatch { stored, requested });
+                }
+                AlgorithmMismatch::Rehash => {
+                    eprintln!("State was hashed with {stored}, rehashing with {requested}...");
+                    let paths = self.hashes.keys().cloned().collect::<Vec<_>>();
+                    paths.into_iter().for_each(|p| self.remove_entry(p));
+                }
+            },
+            _ => {}
+        }
+        self.algorithm = Some(requested.to_string());
+        self.changes.algorithm = true;
+        Ok(())
+    }
+
+    /// Garbage-collects the state.
+    ///
+    /// Files which no longer exist on disk are removed from their roots, entries which are not
+    /// part of any root are dropped, and entries whose files have changed are rehashed.
+    pub fn gc(&mut self, options: &Options) -> Result<ScanReport> {
+        self.check_algorithm(options.algorithm.name(), options.on_algorithm_mismatch)?;
+        let roots = self.roots.iter();
+        let roots = roots.map(|(r, root)| {
+            let files = root.files.iter();
+            let files = files.filter(|p| p.symlink_metadata().is_ok());
+            let files = files.cloned().collect();
+            (r.clone(), files)
+        });
+        let roots = roots.collect::<Vec<_>>();
+        roots.into_iter().for_each(|(r, files)| self.set_root_files(r, files));
+        let dropped = self.prune();
+        let files = self.roots.values().flat_map(|r| r.files.iter());
+        let files = files.cloned().collect::<BTreeSet<_>>();
+        let files = files.into_iter().collect();
+        let mut report = hash(self, files, options)?;
+        report.stats.dropped = dropped;
+        Ok(report)
+    }
+
+    /// Marks every root and entry as changed, so that the next save writes the state in full,
+    /// replacing whatever the store held before.
+    ///
+    /// Used when moving a state between stores.
+    pub fn mark_all_changed(&mut self) {
+        let Self { roots, hashes, changes, .. } = self;
+        changes.replaced = true;
+        changes.algorithm = true;
+        changes.upserted.extend(hashes.keys().cloned());
+        changes.roots.extend(roots.keys().cloned());
+        roots.iter().for_each(|(r, root)| {
+            let files = root.files.iter();
+            let files = files.map(|p| (r.clone(), p.clone()));
+            changes.root_files_added.extend(files);
+        });
+    }
+
+    /// Drops all entries which are not part of any root, returning the number of dropped entries.
+    fn prune(&mut self) -> usize {
+        let files = self.roots.values().flat_map(|r| r.files.iter());
+        let files = files.collect::<BTreeSet<_>>();
+        let stale = self.hashes.keys().filter(|p| !files.contains(p));
+        let stale = stale.cloned().collect::<Vec<_>>();
+        let dropped = stale.len();
+        stale.into_iter().for_each(|p| self.remove_entry(p));
+        dropped
+    }
+
+    fn root_entries(&self, root: &Path) -> impl Iterator<Item = (&PathBuf, &Entry)> {
+        let files = self.roots.get(root).map(|r| &r.files);
+        let files = files.into_iter().flatten();
+        files.filter_map(|p| self.hashes.get_key_value(p))
+    }
+
+    /// Returns the entries of the files of `root` which are under `dir`, sorted by path.
+    fn dir_entries<'a>(
+        &'a self,
+        root: &Path,
+        dir: &'a Path,
+    ) -> impl Iterator<Item = (&'a PathBuf, &'a Entry)> {
+        let from = (Bound::Included(dir), Bound::Unbounded);
+        let files = self.roots.get(root).map(|r| r.files.range::<Path, _>(from));
+        let files = files.into_iter().flatten().take_while(move |p| p.starts_with(dir));
+        files.filter_map(|p| self.hashes.get_key_value(p))
+    }
+
+    fn insert_entry(&mut self, path: PathBuf, entry: Entry) {
+        self.changes.removed.remove(&path);
+        self.changes.upserted.insert(path.clone());
+        self.hashes.insert(path, entry);
+    }
+
+    fn remove_entry(&mut self, path: PathBuf) {
+        self.hashes.remove(&path);
+        self.changes.upserted.remove(&path);
+        self.changes.removed.insert(path);
+    }
+
+    /// Replaces the files of `root`, creating the root if necessary.
+    fn set_root_files(&mut self, root: PathBuf, files: BTreeSet<PathBuf>) {
+        let Self { roots, changes, .. } = self;
+        let root_state = roots.entry(root.clone()).or_default();
+        let removed = root_state.files.difference(&files);
+        let removed = removed.map(|p| (root.clone(), p.clone()));
+        let removed = removed.collect::<Vec<_>>();
+        let added = files.difference(&root_state.files);
+        let added = added.map(|p| (root.clone(), p.clone()));
+        let added = added.collect::<Vec<_>>();
+        removed.into_iter().for_each(|f| {
+            changes.root_files_added.remove(&f);
+            changes.root_files_removed.insert(f);
+        });
+        added.into_iter().for_each(|f| {
+            changes.root_files_removed.remove(&f);
+            changes.root_files_added.insert(f);
+        });
+        changes.roots.insert(root);
+        root_state.files = files;
+    }
+
+    fn set_last_scan(&mut self, root: &Path, stats: Stats) {
+        if let Some(root_state) = self.roots.get_mut(root) {
+            root_state.last_scan = stats;
+            self.changes.roots.insert(root.to_path_buf());
+        }
+    }
+}
+
+
+
+// ===============
+// === Changes ===
+// ===============
+
+/// Modifications made to a [`State`] since it was loaded or last saved.
+#[derive(Clone, Debug, Default)]
+struct Changes {
+    /// Whether the whole state replaces the stored one, rather than updating it.
+    replaced: bool,
+    /// Whether the recorded hash algorithm was modified.
+    algorithm: bool,
+    /// Entries which were inserted or modified.
+    upserted: BTreeSet<PathBuf>,
+    /// Entries which were removed.
+    removed: BTreeSet<PathBuf>,
+    /// Roots which were created or whose bookkeeping was modified.
+    roots: BTreeSet<PathBuf>,
+    /// `(root, file)` pairs which were added to a root.
+    root_files_added: BTreeSet<(PathBuf, PathBuf)>,
+    /// `(root, file)` pairs which were removed from a root.
+    root_files_removed: BTreeSet<(PathBuf, PathBuf)>,
+}
+
+
+
+// ============
+// === Root ===
+// ============
+
+/// Bookkeeping for a single search root.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[must_use]
+pub struct Root {
+    #[serde(with = "paths::set")]
+    files: BTreeSet<PathBuf>,
+    /// Statistics about the most recent scan of this root.
+    #[serde(default)]
+    last_scan: Stats,
+}
+
+// === Main `impl` ===
+
+impl Root {
+    pub fn files(&self) -> &BTreeSet<PathBuf> {
+        &self.files
+    }
+
+    pub fn last_scan(&self) -> Stats {
+        self.last_scan
+    }
 }
 
-#[inline]
-pub fn assert_path_rules(p: impl AsRef<Path>) {
-    let p = path_to_str(&p);
-    assert!(!p.contains('\r'), "Unsupported character in path");
-    assert!(!p.is_empty(), "Empty path");
-    assert_eq!(p, p.trim(), "Extra whitespace in path");
+
+
+// =============
+// === Stats ===
+// =============
+
+/// Counts of what happened to the cached entries during a scan or a garbage collection.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[must_use]
+pub struct Stats {
+    /// Entries removed because their file vanished or is no longer part of any root.
+    pub dropped: usize,
+    /// Entries whose cached hash was still valid.
+    pub kept: usize,
+    /// Entries which were (re)hashed because they were new or had changed.
+    pub refreshed: usize,
 }
 
-#[inline]
-pub fn path_to_str(p: &impl AsRef<Path>) -> &str {
-    let p = p.as_ref();
-    let p = p.to_str();
-    p.expect("Path should be valid UTF-8")
+// === Trait `impl`s ===
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Self { dropped, kept, refreshed } = self;
+        write!(f, "dropped: {dropped}, kept: {kept}, refreshed: {refreshed}")
+    }
 }
 
-#[inline]
-pub fn path_to_string(p: impl AsRef<Path>) -> String {
-    let p = path_to_str(&p);
-    p.to_string()
+
+
+/// Returns the default location of the state file for the given profile and store.
+///
+/// State is kept under `$XDG_STATE_HOME/dupdir` (or `~/.local/state/dupdir` if that is unset), as
+/// e.g. `state.json` for the default profile and `<profile>/state.json` for named profiles. If
+/// neither location can be determined, falls back to the current directory.
+pub fn state_path(profile: Option<&str>, store: StoreKind) -> PathBuf {
+    let dir = xdg_dir("XDG_STATE_HOME", &[".local", "state"]);
+    let Some(dir) = dir else {
+        return PathBuf::from(store.file_name());
+    };
+    let dir = dir.join(STATE_DIR);
+    let dir = match profile {
+        Some(profile) => dir.join(profile),
+        None => dir,
+    };
+    dir.join(store.file_name())
+}
+
+/// Returns the location of the user's exclude patterns, applied to every scan by the CLI.
+///
+/// The file is `$XDG_CONFIG_HOME/dupdir/ignore` (or `~/.config/dupdir/ignore` if that is unset),
+/// in `.gitignore` format. Returns `None` if neither location can be determined.
+pub fn ignore_file_path() -> Option<PathBuf> {
+    let dir = xdg_dir("XDG_CONFIG_HOME", &[".config"])?;
+    Some(dir.join(STATE_DIR).join(IGNORE_FILE))
+}
+
+/// Returns the absolute directory in the environment variable `var`, or `fallback` under the
+/// home directory.
+fn xdg_dir(var: &str, fallback: &[&str]) -> Option<PathBuf> {
+    let dir = env::var_os(var);
+    let dir = dir.map(PathBuf::from).filter(|d| d.is_absolute());
+    dir.or_else(|| {
+        let home = env::var_os("HOME");
+        let home = home.map(PathBuf::from).filter(|d| d.is_absolute());
+        home.map(|h| fallback.iter().fold(h, |d, c| d.join(c)))
+    })
+}
+
+// =============
+// === Entry ===
+// =============
+
+/// A cached file hash, along with the metadata of the file at the time it was hashed.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[must_use]
+pub struct Entry {
+    pub hash: String,
+    pub metadata: Metadata,
+    /// How much of the file was read to compute [`hash`](Self::hash).
+    #[serde(default, skip_serializing_if = "Stage::is_full")]
+    pub stage: Stage,
+}
+
+
+
+// =============
+// === Stage ===
+// =============
+
+/// How much of a file was read to hash it, when scanning with [`Options::staged`].
+///
+/// A file is only read as far as needed to tell it apart from the other files of its root, so two
+/// files of a root have the same hash exactly when they have the same contents, whatever stage
+/// they reached. Hashes of different stages never match, as each stage prefixes what it hashes.
+/// Hashes of files which weren't read in full are however meaningless outside of their root.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// Nothing was read, as no other file had the same size. The hash only stands for the size.
+    Size,
+    /// Only the start and the end of the file were read, as no other file of the same size had
+    /// the same ones.
+    Sample,
+    /// The whole file was read.
+    #[default]
+    Full,
+}
+
+// === Main `impl` ===
+
+impl Stage {
+    pub fn is_full(&self) -> bool {
+        *self == Self::Full
+    }
+}
+
+
+
+// ================
+// === Metadata ===
+// ================
+
+/// The subset of a file's metadata used to decide whether a cached hash is still valid.
+///
+/// If any of these fields differ from the file on disk, the file is considered modified and is
+/// rehashed.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[must_use]
+pub struct Metadata {
+    pub size: u64,
+    /// Modification time, in nanoseconds since the Unix epoch.
+    pub mtime_ns: i64,
+    pub inode: u64,
+    pub device: u64,
+}
+
+// === Main `impl` ===
+
+impl Metadata {
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Self::from(&metadata))
+    }
+
+    /// Whether the metadata identifies the file's inode, so that hardlinks to the same file have
+    /// equal metadata and other files don't.
+    ///
+    /// This is not the case on platforms where the inode isn't available.
+    pub fn identifies_inode(&self) -> bool {
+        self.inode != 0
+    }
+}
+
+// === Trait `impl`s ===
+
+#[cfg(unix)]
+impl From<&fs::Metadata> for Metadata {
+    fn from(metadata: &fs::Metadata) -> Self {
+        use std::os::unix::fs::MetadataExt as _;
+
+        let size = metadata.size();
+        let mtime_ns = metadata.mtime().saturating_mul(1_000_000_000);
+        let mtime_ns = mtime_ns.saturating_add(metadata.mtime_nsec());
+        let inode = metadata.ino();
+        let device = metadata.dev();
+        Self { size, mtime_ns, inode, device }
+    }
+}
+
+#[cfg(not(unix))]
+impl From<&fs::Metadata> for Metadata {
+    fn from(metadata: &fs::Metadata) -> Self {
+        let size = metadata.len();
+        let mtime = metadata.modified().ok();
+        let mtime = mtime.and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok());
+        let mtime_ns = mtime.and_then(|d| i64::try_from(d.as_nanos()).ok()).unwrap_or_default();
+        Self { size, mtime_ns, inode: 0, device: 0 }
+    }
 }
 
 
@@ -110,45 +597,497 @@ pub fn path_to_string(p: impl AsRef<Path>) -> String {
 // === run_all ===
 // ===============
 
-pub fn run_all(
-    state: &mut crate::State,
-    search_path: &str,
-) -> Vec<String> {
+pub fn run_all(state: &mut crate::State, search_path: &Path, options: &Options) -> Result<Report> {
+    let mut scan = scan(state, search_path, options)?;
+    let mut verified = verify_files(state, search_path, options)?;
+    eprintln!("Computing directory hashes...");
+    let mut dir_hashes = dir_hashes(state, search_path, options);
+    verify_dirs(state, search_path, &mut dir_hashes, &mut verified, options);
+    eprintln!("Finding duplicate directories...");
+    let dup_dirs = dup_dirs(&dir_hashes);
+    let mut dup_dirs = if options.expanded { dup_dirs } else { maximal_dup_dirs(dup_dirs) };
+    mark_hardlinks(state, search_path, &mut dup_dirs, options);
+    scan.errors.append(&mut verified.errors);
+    let collisions = verified.collisions;
+    Ok(Report { scan, dup_dirs, collisions })
+}
+
+/// Finds and hashes the files under `search_path`, updates the state's bookkeeping for that root
+/// and saves the state.
+pub fn scan(state: &mut crate::State, search_path: &Path, options: &Options) -> Result<ScanReport> {
+    state.check_algorithm(options.algorithm.name(), options.on_algorithm_mismatch)?;
     eprintln!("Searching for files...");
-    let files = WalkDirFinder::new(search_path);
-    let files = files.into_iter();
-    let files = files.collect();
-    state.files = files;
+    let mut errors = Vec::new();
+    let mut files = Vec::new();
+    let mut skipped = Skipped::default();
+    let found = WalkDirFinder::with_options(search_path, options);
+    let found = found.on_skipped(|path, kind| {
+        if options.log_skipped {
+            eprintln!("Skipping {kind}: {}", escape_path(path));
+        }
+        skipped.add(kind);
+    });
+    found.into_iter().try_for_each(|f| {
+        match f {
+            Ok(f) => files.push(f),
+            Err(e) => errors.push(options.on_file_error.handle(e)?),
+        }
+        Ok::<_, Error>(())
+    })?;
+    let root = search_path.to_path_buf();
+    state.set_root_files(root.clone(), files.iter().cloned().collect());
     eprintln!("Hashing files...");
-    hash(state);
+    let mut report = hash(state, files, options)?;
+    eprintln!("Pruning stale entries...");
+    report.stats.dropped = state.prune();
+    report.errors.splice(0..0, errors);
+    report.skipped = skipped;
+    let stats = report.stats;
+    eprintln!("Scan summary: {stats}");
+    state.set_last_scan(&root, stats);
     eprintln!("Saving hashes...");
-    state.save();
-    eprintln!("Computing directory hashes...");
-    let dir_hashes = dir_hashes(state);
-    eprintln!("Finding duplicate directories...");
-    dup_dirs(&dir_hashes)
+    state.save()?;
+    Ok(report)
 }
 
 
 
+// ==============
+// === Report ===
+// ==============
+
+/// The result of [`run_all`].
+#[derive(Debug)]
+#[must_use]
+pub struct Report {
+    pub scan: ScanReport,
+    /// Duplicate directories, sorted by path.
+    pub dup_dirs: Vec<DupDir>,
+    /// The number of groups of files or directories which were split under [`Options::verify`],
+    /// as their hashes collided. Files which couldn't be read to verify them are added to the
+    /// errors of the scan.
+    pub collisions: usize,
+}
+
+
+
+// ==================
+// === ScanReport ===
+// ==================
+
+/// The result of hashing the files of a root, or of a garbage collection.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct ScanReport {
+    pub stats: Stats,
+    /// Files and directories which could not be read, when collected under
+    /// [`ErrorPolicy::Collect`]. These files are left out of the state.
+    pub errors: Vec<Error>,
+    /// Symlinks and special files which were found but not scanned.
+    pub skipped: Skipped,
+    /// Files which were (re)hashed by reusing the hash of another hardlink to the same inode,
+    /// rather than by reading them again. These are included in [`Stats::refreshed`].
+    pub hardlinks: usize,
+}
+
+
+
+// ===============
+// === Options ===
+// ===============
+
+/// Options controlling a scan.
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct Options {
+    /// The algorithm files and directories are hashed with. It is recorded in the state, whose
+    /// entries are only reused when hashed with the same algorithm, as described by
+    /// [`AlgorithmMismatch`].
+    pub algorithm: Algorithm,
+    /// How often hashing progress is saved to the state file, or `None` to only save once all
+    /// files have been hashed.
+    pub checkpoint_interval: Option<Duration>,
+    /// When set (e.g. from a signal handler), hashing stops early and the progress made so far is
+    /// saved. The next scan resumes from there, since unchanged files are not rehashed.
+    pub interrupt: Arc<AtomicBool>,
+    /// What to do when the state was hashed with a different algorithm.
+    pub on_algorithm_mismatch: AlgorithmMismatch,
+    /// What to do when a file or directory can't be read.
+    pub on_file_error: ErrorPolicy,
+    /// How symbolic links found during a scan are treated.
+    pub symlinks: SymlinkPolicy,
+    /// Don't descend into directories on a different file system than the search root.
+    pub one_file_system: bool,
+    /// Print every skipped symlink, FIFO, socket or device node, rather than only counting them.
+    pub log_skipped: bool,
+    /// Which files and directories are scanned.
+    pub filter: Filter,
+    /// Names of files which are scanned, but left out of the hashes of their directories, e.g.
+    /// the `.DS_Store` files macOS leaves behind. Names are compared ignoring ASCII case.
+    pub junk_files: Vec<String>,
+    /// Which contents make two directories duplicates of each other.
+    pub dir_hash_mode: DirHashMode,
+    /// Leave the search root out of the directory hashes, and so out of the duplicates, leaving
+    /// only the directories under it.
+    pub exclude_root: bool,
+    /// Report every group of duplicate directories, including those whose members all lie inside
+    /// the members of another group.
+    pub expanded: bool,
+    /// How [`similar_dirs`] measures the similarity of two directories.
+    pub similarity: Similarity,
+    /// The similarity, between `0` and `1`, from which [`similar_dirs`] reports two directories.
+    pub min_similarity: f64,
+    /// Only read as much of each file as needed to tell it apart from the other files of the root,
+    /// as described by [`Stage`], rather than hashing every file in full.
+    pub staged: bool,
+    /// Confirm that files sharing a hash have the same contents before reporting them as
+    /// duplicates, as described by [`verify_files`] and [`verify_dirs`], rather than trusting
+    /// the hashes.
+    pub verify: bool,
+}
+
+// === Main `impl` ===
+
+impl Options {
+    /// Whether the file at `path` is one of the [`junk_files`](Self::junk_files).
+    pub fn is_junk_file(&self, path: &Path) -> bool {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            return false;
+        };
+        self.junk_files.iter().any(|f| f.eq_ignore_ascii_case(name))
+    }
+
+    /// Whether a file is compared by its contents with others. Empty files are all identical, so
+    /// they would match unrelated files and directories, and junk files don't matter.
+    pub(crate) fn is_compared(&self, path: &Path, entry: &Entry) -> bool {
+        entry.metadata.size > 0 && !self.is_junk_file(path)
+    }
+}
+
+// === Trait `impl`s ===
+
+impl Default for Options {
+    fn default() -> Self {
+        let algorithm = DEFAULT_ALGORITHM;
+        let checkpoint_interval = Some(DEFAULT_CHECKPOINT_INTERVAL);
+        let interrupt = Arc::default();
+        let on_algorithm_mismatch = AlgorithmMismatch::default();
+        let on_file_error = ErrorPolicy::default();
+        let symlinks = SymlinkPolicy::default();
+        let one_file_system = false;
+        let log_skipped = false;
+        let filter = Filter::default();
+        let junk_files = DEFAULT_JUNK_FILES.iter().map(|f| f.to_string()).collect();
+        let dir_hash_mode = DirHashMode::default();
+        let exclude_root = false;
+        let expanded = false;
+        let similarity = Similarity::default();
+        let min_similarity = DEFAULT_MIN_SIMILARITY;
+        let staged = true;
+        let verify = false;
+        Self {
+            algorithm,
+            checkpoint_interval,
+            interrupt,
+            on_algorithm_mismatch,
+            on_file_error,
+            symlinks,
+            one_file_system,
+            log_skipped,
+            filter,
+            junk_files,
+            dir_hash_mode,
+            exclude_root,
+            expanded,
+            similarity,
+            min_similarity,
+            staged,
+            verify,
+        }
+    }
+}
+
+
+
+// =========================
+// === AlgorithmMismatch ===
+// =========================
+
+/// Policy for a state whose entries were hashed with a different algorithm than requested.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum AlgorithmMismatch {
+    /// Fail with [`Error::AlgorithmMismatch`], leaving the state untouched.
+    #[default]
+    Refuse,
+    /// Discard the cached entries and rehash every file.
+    Rehash,
+}
+
+// === Trait `impl`s ===
+
+impl str::FromStr for AlgorithmMismatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let policy = match s {
+            "refuse" => Self::Refuse,
+            "rehash" => Self::Rehash,
+            _ => Err(format!("Invalid algorithm mismatch policy: {s}"))?,
+        };
+        Ok(policy)
+    }
+}
+
+
+
+// =====================
+// === SymlinkPolicy ===
+// =====================
+
+/// Policy for symbolic links found during a scan.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SymlinkPolicy {
+    /// Leave symlinks out of the scan, counting them in [`Skipped::symlinks`].
+    #[default]
+    Skip,
+    /// Scan the files and directories symlinks point to, as if they were found at the symlink's
+    /// path. Symlink loops are reported as errors.
+    Follow,
+    /// Record symlinks as files of their own, whose content is the path they point to. Two
+    /// symlinks are then duplicates if they have the same target, whether or not it exists.
+    Record,
+}
+
+// === Trait `impl`s ===
+
+impl str::FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let policy = match s {
+            "skip" => Self::Skip,
+            "follow" => Self::Follow,
+            "record" => Self::Record,
+            _ => Err(format!("Invalid symlink policy: {s}"))?,
+        };
+        Ok(policy)
+    }
+}
+
+
+
+// ===================
+// === DirHashMode ===
+// ===================
+
+/// How the hash of a directory is computed from the files under it.
+///
+/// The order in which files are listed never matters. In the [`Multiset`](Self::Multiset) and
+/// [`Set`](Self::Set) modes their names and the layout of subdirectories don't either, only the
+/// files' contents.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DirHashMode {
+    /// Directories are duplicates if they hold the same contents the same number of times.
+    #[default]
+    Multiset,
+    /// Directories are duplicates if they hold the same distinct contents, however many copies
+    /// of each. A directory with a single file then matches one with that file three times.
+    Set,
+    /// Directories are duplicates if they hold the same contents at the same paths relative to
+    /// the directory, i.e. if they are identical trees of files.
+    RelativePaths,
+    /// Directories are duplicates under the same condition as in the
+    /// [`RelativePaths`](Self::RelativePaths) mode, but the hash of a directory is computed from
+    /// the sorted names and hashes of its own entries, those of subdirectories included, rather
+    /// than from a sum over its files. This takes memory proportional to the number of entries
+    /// of the open directories, in exchange for hashes only equal for identical trees.
+    Merkle,
+}
+
+// === Trait `impl`s ===
+
+impl str::FromStr for DirHashMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mode = match s {
+            "multiset" => Self::Multiset,
+            "set" => Self::Set,
+            "relative-paths" => Self::RelativePaths,
+            "merkle" => Self::Merkle,
+            _ => Err(format!("Invalid directory hash mode: {s}"))?,
+        };
+        Ok(mode)
+    }
+}
+
+
+
+// ================
+// === FileKind ===
+// ================
+
+/// Kinds of directory entries which are not scanned as regular files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FileKind {
+    Symlink,
+    Fifo,
+    Socket,
+    /// A block or character device node.
+    Device,
+    /// Anything else that is neither a file nor a directory.
+    Other,
+}
+
+// === Main `impl` ===
+
+impl FileKind {
+    fn of(file_type: fs::FileType) -> Self {
+        if file_type.is_symlink() {
+            return Self::Symlink;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt as _;
+
+            if file_type.is_fifo() {
+                return Self::Fifo;
+            }
+            if file_type.is_socket() {
+                return Self::Socket;
+            }
+            if file_type.is_block_device() || file_type.is_char_device() {
+                return Self::Device;
+            }
+        }
+        Self::Other
+    }
+}
+
+// === Trait `impl`s ===
+
+impl fmt::Display for FileKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::Symlink => "symlink",
+            Self::Fifo => "FIFO",
+            Self::Socket => "socket",
+            Self::Device => "device node",
+            Self::Other => "special file",
+        };
+        f.write_str(kind)
+    }
+}
+
+
+
+// ===============
+// === Skipped ===
+// ===============
+
+/// Counts of the directory entries left out of a scan, by [`FileKind`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[must_use]
+pub struct Skipped {
+    pub symlinks: usize,
+    pub fifos: usize,
+    pub sockets: usize,
+    pub devices: usize,
+    pub other: usize,
+}
+
+// === Main `impl` ===
+
+impl Skipped {
+    pub fn add(&mut self, kind: FileKind) {
+        let count = match kind {
+            FileKind::Symlink => &mut self.symlinks,
+            FileKind::Fifo => &mut self.fifos,
+            FileKind::Socket => &mut self.sockets,
+            FileKind::Device => &mut self.devices,
+            FileKind::Other => &mut self.other,
+        };
+        *count += 1;
+    }
+
+    pub fn total(&self) -> usize {
+        let Self { symlinks, fifos, sockets, devices, other } = self;
+        symlinks + fifos + sockets + devices + other
+    }
+}
+
+// === Trait `impl`s ===
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let Self { symlinks, fifos, sockets, devices, other } = self;
+        write!(f, "symlinks: {symlinks}, FIFOs: {fifos}, sockets: {sockets}, ")?;
+        write!(f, "devices: {devices}, other: {other}")
+    }
+}
+
+
 
 // =====================
 // === WalkdirFinder ===
 // =====================
 
+/// Finds the files under a path with [`walkdir`].
+///
+/// Directories are descended into and regular files are yielded, unless left out by a [`Filter`].
+/// Symlinks are handled according to a [`SymlinkPolicy`], and other special files are never
+/// yielded; both are passed to the [`on_skipped`](Self::on_skipped) callback when left out.
 #[must_use]
 pub struct WalkDirFinder<'a> {
     entries: WalkDir,
-    marker: PhantomData<&'a ()>,
+    filter: FilterWalk,
+    symlinks: SymlinkPolicy,
+    on_skipped: Option<OnSkipped<'a>>,
 }
 
+/// Callback for the entries a [`WalkDirFinder`] leaves out.
+type OnSkipped<'a> = Box<dyn FnMut(&Path, FileKind) + 'a>;
+
 
 // === Main `impl` ===
 
-impl WalkDirFinder<'_> {
-    pub fn new(path: &str) -> Self {
+impl<'a> WalkDirFinder<'a> {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
         let entries = WalkDir::new(path);
-        Self { entries, marker: PhantomData }
+        let filter = FilterWalk::new(Filter::default(), path.to_path_buf());
+        let symlinks = SymlinkPolicy::default();
+        Self { entries, filter, symlinks, on_skipped: None }
+    }
+
+    /// Applies the filter, symlink and file system boundary settings of `options`.
+    pub fn with_options(path: impl AsRef<Path>, options: &Options) -> Self {
+        let finder = Self::new(path);
+        let finder = finder.filter(options.filter.clone());
+        let finder = finder.symlinks(options.symlinks);
+        finder.one_file_system(options.one_file_system)
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = self.filter.with_filter(filter);
+        self
+    }
+
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.entries = self.entries.follow_links(policy == SymlinkPolicy::Follow);
+        self.symlinks = policy;
+        self
+    }
+
+    pub fn one_file_system(mut self, enabled: bool) -> Self {
+        self.entries = self.entries.same_file_system(enabled);
+        self
+    }
+
+    /// Sets a callback for the entries which are left out because of their [`FileKind`].
+    pub fn on_skipped(mut self, f: impl FnMut(&Path, FileKind) + 'a) -> Self {
+        self.on_skipped = Some(Box::new(f));
+        self
     }
 }
 
@@ -156,19 +1095,31 @@ impl WalkDirFinder<'_> {
 // === Trait `impl`s ===
 
 impl<'a> IntoIterator for WalkDirFinder<'a> {
-    type Item = PathBuf;
+    type Item = Result<PathBuf>;
     type IntoIter = FinderIter<'a, PathBuf>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let entries = self.entries.into_iter();
-        let paths = entries.filter_map(|e| {
-            let e = e.expect("Failed to read entry");
+        let Self { entries, mut filter, symlinks, mut on_skipped } = self;
+        let entries = entries.into_iter();
+        let entries = entries.filter_entry(move |e| filter.visit(e));
+        let paths = entries.filter_map(move |e| {
+            let e = match e {
+                Ok(e) => e,
+                Err(e) => return Some(Err(e.into())),
+            };
             let file_type = e.file_type();
-            if !file_type.is_file() {
+            if file_type.is_dir() {
+                return None;
+            }
+            let record = file_type.is_symlink() && symlinks == SymlinkPolicy::Record;
+            if !file_type.is_file() && !record {
+                if let Some(f) = &mut on_skipped {
+                    f(e.path(), FileKind::of(file_type));
+                }
                 return None;
             }
             let path = e.into_path();
-            Some(path)
+            Some(Ok(path))
         });
         let paths = Box::new(paths);
         Self::IntoIter { paths }
@@ -183,7 +1134,7 @@ impl<'a> IntoIterator for WalkDirFinder<'a> {
 
 #[must_use]
 pub struct FinderIter<'a, P> {
-    pub paths: Box<dyn Iterator<Item = P> + 'a>,
+    pub paths: Box<dyn Iterator<Item = Result<P>> + 'a>,
 }
 
 // === Trait `impl`s ===
@@ -192,14 +1143,15 @@ impl<'a, P> Iterator for FinderIter<'a, P>
 where
     P: AsRef<Path> + 'a,
 {
-    type Item = P;
+    type Item = Result<P>;
 
     fn next(&mut self) -> Option<Self::Item> {
         match self.paths.next() {
-            Some(path) => {
-                assert_path_rules(&path);
+            Some(Ok(path)) => {
+                let path = check_path_rules(&path).map(|()| path);
                 Some(path)
             },
+            Some(Err(e)) => Some(Err(e)),
             None => None,
         }
     }
@@ -208,7 +1160,7 @@ where
 impl<P> Debug for FinderIter<'_, P> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         f.debug_struct("Finder")
-            .field("lines", &"Box<dyn Iterator<Item = P>>")
+            .field("lines", &"Box<dyn Iterator<Item = Result<P>>>")
             .finish()
     }
 }
@@ -219,85 +1171,669 @@ impl<P> Debug for FinderIter<'_, P> {
 // === hash ===
 // ============
 
-fn hash(state: &mut State) {
-    let paths = state.files.clone();
+/// Hashes the given files, reusing cached entries for files which haven't changed.
+///
+/// Under [`Options::staged`], files are only read as far as needed to tell them apart from the
+/// other given files, as described by [`Stage`]. Otherwise every file is hashed in full.
+///
+/// Progress is saved to the state file every [`Options::checkpoint_interval`], and when the scan
+/// is interrupted. Reports the number of entries that were kept and refreshed; `dropped` is always
+/// zero.
+fn hash(state: &mut State, paths: Vec<PathBuf>, options: &Options) -> Result<ScanReport> {
+    let mut report = ScanReport::default();
+    let sized = size_files(state, &paths, options);
+    sized.into_iter().try_for_each(|(p, sized)| {
+        match sized {
+            Ok(None) => report.stats.kept += 1,
+            Ok(Some(entry)) => {
+                report.stats.refreshed += 1;
+                state.insert_entry(p, entry);
+            }
+            Err(e) => {
+                report.errors.push(options.on_file_error.handle(e)?);
+                // Don't keep serving a stale hash for a file that can no longer be read.
+                state.remove_entry(p);
+            }
+        }
+        Ok::<_, Error>(())
+    })?;
+    let paths = match options.staged {
+        true => sample_files(state, &paths, &mut report, options)?,
+        false => {
+            let unread = |p: &&PathBuf| state.hashes.get(*p).is_some_and(|e| !e.stage.is_full());
+            paths.iter().filter(unread).cloned().collect()
+        },
+    };
+    hash_files(state, paths, &mut report, options)?;
+    Ok(report)
+}
+
+/// Stats the given files, returning `None` for those whose cached entry is still valid, and a new
+/// entry at [`Stage::Size`] for the others. Recorded symlinks are hashed right away, as that only
+/// takes reading the link.
+fn size_files(
+    state: &State,
+    paths: &[PathBuf],
+    options: &Options,
+) -> Vec<(PathBuf, Result<Option<Entry>>)> {
     #[cfg(feature = "rayon")]
-    let paths = paths.into_par_iter();
+    let sized = paths.par_iter();
     #[cfg(not(feature = "rayon"))]
-    let paths = paths.into_iter();
-    let paths = paths.progress();
-    let hashes = paths.map(|p| {
-        match state.hashes.get(&p) {
-            Some(hash) => {
-                let hash = hash.as_str();
-                let hash = hash.to_string();
-                (p, hash)
+    let sized = paths.iter();
+    let sized = sized.progress_count(paths.len() as u64);
+    let sized = sized.map(|p| {
+        let (metadata, is_symlink) = match read_metadata(p, options.symlinks) {
+            Ok(metadata) => metadata,
+            Err(e) => return (p.clone(), Err(e)),
+        };
+        // Only reuse the cached entry if the file hasn't changed since it was hashed.
+        let cached = state.hashes.get(p).filter(|e| e.metadata == metadata);
+        let entry = match (cached, is_symlink) {
+            (Some(_), _) => Ok(None),
+            (None, true) => {
+                let hash = hash_symlink(p, options.algorithm);
+                hash.map(|hash| Some(Entry { hash, metadata, stage: Stage::Full }))
             },
-            None => {
-                let path = path_to_str(&p);
-                let hash = hash_path(path);
-                (p, hash)
+            (None, false) => {
+                let hash = hash_size(metadata.size, options.algorithm);
+                Ok(Some(Entry { hash, metadata, stage: Stage::Size }))
+            },
+        };
+        (p.clone(), entry)
+    });
+    sized.collect()
+}
+
+/// Samples the given files which share their size with another, returning the files which have to
+/// be hashed in full.
+///
+/// Those are the files sharing their sample with another, and the files sharing their size with a
+/// file which was already hashed in full, whose sample is unknown. Files with a unique sample are
+/// moved to [`Stage::Sample`].
+fn sample_files(
+    state: &mut State,
+    paths: &[PathBuf],
+    report: &mut ScanReport,
+    options: &Options,
+) -> Result<Vec<PathBuf>> {
+    let interrupt = &options.interrupt;
+    let mut sizes = HashMap::<u64, Vec<(&PathBuf, &Entry)>>::new();
+    let entries = paths.iter().filter_map(|p| state.hashes.get_key_value(p));
+    entries.for_each(|(p, e)| sizes.entry(e.metadata.size).or_default().push((p, e)));
+    let groups = sizes.into_values().filter(|g| g.len() > 1);
+    let groups = groups.filter(|g| g.iter().any(|(_, e)| !e.stage.is_full()));
+    let has_full = |g: &Vec<(&PathBuf, &Entry)>| g.iter().any(|(_, e)| e.stage.is_full());
+    let (full, groups) = groups.partition::<Vec<_>, _>(has_full);
+    let full = full.into_iter().flatten().filter(|(_, e)| !e.stage.is_full());
+    let mut to_hash = full.map(|(p, _)| p.clone()).collect::<Vec<_>>();
+    let to_sample = groups.iter().flatten().filter(|(_, e)| e.stage == Stage::Size);
+    let to_sample = to_sample.map(|(p, e)| (*p, e.metadata.size)).collect::<Vec<_>>();
+
+    eprintln!("Sampling files...");
+    let len = to_sample.len() as u64;
+    #[cfg(feature = "rayon")]
+    let to_sample = to_sample.into_par_iter();
+    #[cfg(not(feature = "rayon"))]
+    let to_sample = to_sample.into_iter();
+    let samples = to_sample.progress_count(len).filter_map(|(p, size)| {
+        if is_interrupted(interrupt) {
+            return None;
+        }
+        Some((p, sample_path(p, size, options.algorithm)))
+    });
+    let samples = samples.collect::<HashMap<_, _>>();
+
+    // Files sampled by an earlier scan have their sample as their hash.
+    let mut sampled = Vec::new();
+    let mut errors = Vec::new();
+    groups.into_iter().for_each(|group| {
+        let mut by_sample = HashMap::<&str, Vec<(&PathBuf, &Entry)>>::new();
+        group.into_iter().for_each(|(p, e)| {
+            let sample = match e.stage {
+                Stage::Sample => Some(e.hash.as_str()),
+                _ => match samples.get(p) {
+                    Some(Ok(sample)) => Some(sample.as_str()),
+                    Some(Err(_)) | None => None,
+                },
+            };
+            if let Some(sample) = sample {
+                by_sample.entry(sample).or_default().push((p, e));
+            }
+        });
+        by_sample.into_iter().for_each(|(sample, files)| match files[..] {
+            [(p, e)] if e.stage == Stage::Size => {
+                let (hash, metadata) = (sample.to_string(), e.metadata);
+                sampled.push((p.clone(), Entry { hash, metadata, stage: Stage::Sample }));
+            },
+            [_] => {},
+            _ => to_hash.extend(files.into_iter().map(|(p, _)| p.clone())),
+        });
+    });
+    samples.into_iter().for_each(|(p, sample)| {
+        if let Err(e) = sample {
+            errors.push((p.clone(), e));
+        }
+    });
+    sampled.into_iter().for_each(|(p, entry)| state.insert_entry(p, entry));
+    errors.into_iter().try_for_each(|(p, e)| {
+        report.errors.push(options.on_file_error.handle(e)?);
+        state.remove_entry(p);
+        Ok::<_, Error>(())
+    })?;
+    Ok(to_hash)
+}
+
+/// Hashes the given files in full, reusing the hashes of other hardlinks to the same inodes.
+fn hash_files(
+    state: &mut State,
+    paths: Vec<PathBuf>,
+    report: &mut ScanReport,
+    options: &Options,
+) -> Result<()> {
+    let interrupt = &options.interrupt;
+    let bar = ProgressBar::new(paths.len() as u64);
+    let mut last_checkpoint = Instant::now();
+    // Hashes of the inodes seen so far, so that each inode is read at most once.
+    let inodes = state.hashes.values().filter_map(inode_hash);
+    let mut inodes = inodes.collect::<HashMap<_, _>>();
+    eprintln!("Reading files...");
+    for paths in paths.chunks(CHECKPOINT_CHUNK_SIZE) {
+        if is_interrupted(interrupt) {
+            break;
+        }
+        let hashes = hash_chunk(state, paths, &bar, &inodes, options);
+        hashes.into_iter().try_for_each(|(p, hashed)| {
+            match hashed {
+                Ok(Hashed::Kept) => {},
+                Ok(Hashed::Read(entry)) => {
+                    inodes.extend(inode_hash(&entry));
+                    state.insert_entry(p, entry);
+                }
+                Ok(Hashed::Linked(entry)) => {
+                    report.hardlinks += 1;
+                    state.insert_entry(p, entry);
+                }
+                Err(e) => {
+                    report.errors.push(options.on_file_error.handle(e)?);
+                    state.remove_entry(p);
+                }
+            }
+            Ok::<_, Error>(())
+        })?;
+        if let Some(interval) = options.checkpoint_interval {
+            if last_checkpoint.elapsed() >= interval {
+                state.save()?;
+                last_checkpoint = Instant::now();
+            }
+        }
+    }
+    bar.finish();
+    if is_interrupted(interrupt) {
+        state.save()?;
+        return Err(Error::Interrupted);
+    }
+    Ok(())
+}
+
+/// Returns the hash of the inode of a file hashed in full.
+fn inode_hash(entry: &Entry) -> Option<(Metadata, String)> {
+    let identifies_inode = entry.metadata.identifies_inode() && entry.stage.is_full();
+    identifies_inode.then(|| (entry.metadata, entry.hash.clone()))
+}
+
+/// How the entry of a file was obtained by [`hash_chunk`].
+enum Hashed {
+    /// The cached entry is still valid.
+    Kept,
+    /// The file was read and hashed.
+    Read(Entry),
+    /// The hash of another hardlink to the same inode was reused.
+    Linked(Entry),
+}
+
+/// Hashes a chunk of files, returning how the entry of each file was obtained.
+///
+/// All files are stat'ed before any is read, so that hardlinks to the same inode, whether within
+/// the chunk or in `inodes`, are only read once.
+fn hash_chunk(
+    state: &State,
+    paths: &[PathBuf],
+    bar: &ProgressBar,
+    inodes: &HashMap<Metadata, String>,
+    options: &Options,
+) -> Vec<(PathBuf, Result<Hashed>)> {
+    let interrupt = &options.interrupt;
+    #[cfg(feature = "rayon")]
+    let metadata = paths.par_iter();
+    #[cfg(not(feature = "rayon"))]
+    let metadata = paths.iter();
+    let metadata = metadata.map(|p| (p, read_metadata(p, options.symlinks)));
+    let metadata = metadata.collect::<Vec<_>>();
+
+    let mut hashed = Vec::with_capacity(paths.len());
+    // The first path of each inode which has to be read, and the other paths linked to it.
+    let mut to_read = Vec::new();
+    let mut to_link = Vec::new();
+    let mut inodes_to_read = HashSet::new();
+    for (p, metadata) in metadata {
+        let (metadata, is_symlink) = match metadata {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                hashed.push((p.clone(), Err(e)));
+                continue;
+            },
+        };
+        // Only reuse the cached hash if the file hasn't changed since it was hashed in full.
+        let cached = state.hashes.get(p).filter(|e| e.metadata == metadata && e.stage.is_full());
+        let linked = inodes.get(&metadata).filter(|_| metadata.identifies_inode());
+        match (cached, linked) {
+            (Some(_), _) => hashed.push((p.clone(), Ok(Hashed::Kept))),
+            (None, Some(hash)) => {
+                let entry = Entry { hash: hash.clone(), metadata, stage: Stage::Full };
+                hashed.push((p.clone(), Ok(Hashed::Linked(entry))));
+            },
+            (None, None) if inodes_to_read.contains(&metadata) => to_link.push((p, metadata)),
+            (None, None) => {
+                if metadata.identifies_inode() {
+                    inodes_to_read.insert(metadata);
+                }
+                to_read.push((p, metadata, is_symlink));
             },
         }
+    }
+    bar.inc(hashed.len() as u64);
+
+    #[cfg(feature = "rayon")]
+    let to_read = to_read.into_par_iter();
+    #[cfg(not(feature = "rayon"))]
+    let to_read = to_read.into_iter();
+    let to_read = to_read.progress_with(bar.clone());
+    let read = to_read.filter_map(|(p, metadata, is_symlink)| {
+        if is_interrupted(interrupt) {
+            return None;
+        }
+        // Recorded symlinks are hashed as links, rather than as the file they point to.
+        let hash = match is_symlink {
+            true => hash_symlink(p, options.algorithm),
+            false => hash_path(p, options.algorithm),
+        };
+        let entry = hash.map(|hash| Hashed::Read(Entry { hash, metadata, stage: Stage::Full }));
+        Some((p.clone(), entry))
+    });
+    let read = read.collect::<Vec<_>>();
+
+    let read_hashes = read.iter().filter_map(|(_, hashed)| match hashed {
+        Ok(Hashed::Read(entry)) => Some((entry.metadata, entry.hash.as_str())),
+        _ => None,
+    });
+    let read_hashes = read_hashes.collect::<HashMap<_, _>>();
+    // Links whose inode could not be read are left for the next scan.
+    let linked = to_link.into_iter().filter_map(|(p, metadata)| {
+        let hash = read_hashes.get(&metadata)?.to_string();
+        let entry = Entry { hash, metadata, stage: Stage::Full };
+        Some((p.clone(), Ok(Hashed::Linked(entry))))
     });
-    state.hashes = hashes.collect();
+    let linked = linked.collect::<Vec<_>>();
+    bar.inc(linked.len() as u64);
+    hashed.extend(read);
+    hashed.extend(linked);
+    hashed
+}
+
+/// Reads the metadata used to validate a cached entry, along with whether the file is a symlink.
+///
+/// Recorded symlinks are described by their own metadata, rather than that of their target.
+fn read_metadata(path: &Path, symlinks: SymlinkPolicy) -> Result<(Metadata, bool)> {
+    let metadata = match symlinks {
+        SymlinkPolicy::Record => fs::symlink_metadata(path),
+        _ => fs::metadata(path),
+    };
+    let metadata = metadata.map_err(Error::io(path))?;
+    let is_symlink = metadata.file_type().is_symlink();
+    Ok((Metadata::from(&metadata), is_symlink))
 }
 
-fn hash_path(path: &str) -> String {
-    let mut file = fs::File::open(path).unwrap_or_else(|_| panic!("Failed to open file: {path:?}"));
-    let mut hasher = dupdir_hash::T1ha2::default();
-    dupdir_hash::copy_wide(&mut file, &mut hasher).expect("Failed to hash file");
-    let hash = hasher.finish().to_vec();
+/// Whether the scan was interrupted through [`Options::interrupt`]. Files which haven't been
+/// started yet are skipped from then on, while those already being hashed are finished and saved.
+fn is_interrupted(interrupt: &AtomicBool) -> bool {
+    interrupt.load(Ordering::Relaxed)
+}
+
+fn hash_path(path: &Path, algorithm: Algorithm) -> Result<String> {
+    let mut file = fs::File::open(path).map_err(Error::io(path))?;
+    let hash = algorithm.hash(&mut file).map_err(Error::io(path))?;
+    Ok(hex::encode(hash))
+}
+
+/// Hashes the target of a symlink, prefixed so that it never collides with a file whose content
+/// happens to be the same path.
+fn hash_symlink(path: &Path, algorithm: Algorithm) -> Result<String> {
+    let target = fs::read_link(path).map_err(Error::io(path))?;
+    let mut hasher = algorithm.hasher();
+    hasher.update(SYMLINK_HASH_PREFIX);
+    hasher.update(path_to_bytes(&target));
+    let hash = hasher.finish();
+    Ok(hex::encode(hash))
+}
+
+/// Hashes the size of a file, standing for the file while no other file has the same size.
+fn hash_size(size: u64, algorithm: Algorithm) -> String {
+    let mut hasher = algorithm.hasher();
+    hasher.update(SIZE_HASH_PREFIX);
+    hasher.update(&size.to_le_bytes());
+    let hash = hasher.finish();
     hex::encode(hash)
 }
 
+/// Hashes the size of a file along with up to [`SAMPLE_SIZE`] bytes from its start and as many
+/// from its end, which covers the whole of small files.
+fn sample_path(path: &Path, size: u64, algorithm: Algorithm) -> Result<String> {
+    let mut file = fs::File::open(path).map_err(Error::io(path))?;
+    let head = size.min(SAMPLE_SIZE);
+    let tail = (size - head).min(SAMPLE_SIZE);
+    let mut sample = vec![0; (head + tail) as usize];
+    let (head_bytes, tail_bytes) = sample.split_at_mut(head as usize);
+    file.read_exact(head_bytes).map_err(Error::io(path))?;
+    file.seek(io::SeekFrom::Start(size - tail)).map_err(Error::io(path))?;
+    file.read_exact(tail_bytes).map_err(Error::io(path))?;
+    let mut hasher = algorithm.hasher();
+    hasher.update(SAMPLE_HASH_PREFIX);
+    hasher.update(&size.to_le_bytes());
+    hasher.update(&sample);
+    let hash = hasher.finish();
+    Ok(hex::encode(hash))
+}
+
 
 
 // ==================
 // === dir_hashes ===
 // ==================
 
-pub fn dir_hashes(
-    state: &mut crate::State,
-) -> Vec<(String, &Path)> {
-    eprintln!("Mapping file hashes to their ancestors...");
-    let entries = state.hashes.iter();
-    let entries = entries.progress();
-    let mut files_in_dir = BTreeMap::<_, BTreeSet<&str>>::new();
-    entries.for_each(|(p, h)| {
-        let dir = p.parent().expect("Parent");
-        for a in dir.ancestors() {
-            let hashes = files_in_dir.entry(a);
-            // Note that we store the files in a `BTreeSet` rather than incrementally hashing
-            // because the order in which files appear in the directories (e.g., due to renaming)
-            // shouldn't affect the hash.
-            let hashes = hashes.or_insert_with(BTreeSet::new);
-            hashes.insert(h);
+/// Hashes `root` and each directory under it from the hashes of the files they contain,
+/// according to [`Options::dir_hash_mode`]. The root itself is left out under
+/// [`Options::exclude_root`].
+///
+/// Files named in [`Options::junk_files`] are left out, so they don't make otherwise identical
+/// directories differ.
+///
+/// Directories are hashed in a single [`DirWalk`] over the files: each directory is hashed once
+/// all of its files have been seen, and its [`Fingerprint`] is folded into its parent's. Memory is
+/// then proportional to the depth of the tree rather than to the number of (file, ancestor)
+/// pairs, except under [`DirHashMode::Set`] which has to keep the distinct hashes themselves.
+///
+/// Under [`DirHashMode::RelativePaths`], a file's path relative to each enclosing directory
+/// differs, so the file is added to each of them rather than folded in with its directory.
+pub fn dir_hashes<'a>(
+    state: &'a crate::State,
+    root: &Path,
+    options: &Options,
+) -> Vec<(String, &'a Path)> {
+    eprintln!("Hashing directories...");
+    let entries = state.root_entries(root);
+    let entries = entries.filter(|(p, _)| !options.is_junk_file(p));
+    let entries = entries.collect::<Vec<_>>();
+    let (mode, algorithm) = (options.dir_hash_mode, options.algorithm);
+    let mut walk = DirHashes { mode, algorithm, dir_hashes: Vec::new() };
+    walk.walk(entries, root);
+    let mut dir_hashes = walk.dir_hashes;
+    if options.exclude_root {
+        dir_hashes.retain(|(_, d)| *d != root);
+    }
+    dir_hashes
+}
+
+fn file_name_bytes(path: &Path) -> &[u8] {
+    let name = path.file_name().map(Path::new);
+    name.map(path_to_bytes).unwrap_or_default()
+}
+
+
+
+// =================
+// === DirHashes ===
+// =================
+
+/// The [`DirWalk`] of [`dir_hashes`].
+struct DirHashes<'a> {
+    mode: DirHashMode,
+    algorithm: Algorithm,
+    dir_hashes: Vec<(String, &'a Path)>,
+}
+
+// === Trait `impl`s ===
+
+impl<'a> DirWalk<'a> for DirHashes<'a> {
+    type Dir = Fingerprint<'a>;
+
+    fn open_dir(&mut self, _path: &'a Path) -> Self::Dir {
+        Fingerprint::new(self.mode)
+    }
+
+    fn add_file(
+        &mut self,
+        open_dirs: &mut [OpenDir<'a, Self::Dir>],
+        path: &'a Path,
+        entry: &'a Entry,
+    ) {
+        match self.mode {
+            DirHashMode::RelativePaths => open_dirs.iter_mut().for_each(|d| {
+                let relative = path.strip_prefix(d.path).unwrap_or(path);
+                d.contents.add_file(path_to_bytes(relative), &entry.hash, self.algorithm);
+            }),
+            // A root which is a file has no directory to add it to.
+            _ => if let Some(dir) = open_dirs.last_mut() {
+                dir.contents.add_file(file_name_bytes(path), &entry.hash, self.algorithm);
+            },
         }
-    });
+    }
 
-    eprintln!("Finalizing directory hashes...");
-    #[cfg(feature = "rayon")]
-    let files_in_dir = files_in_dir.into_par_iter();
-    #[cfg(not(feature = "rayon"))]
-    let files_in_dir = files_in_dir.into_iter();
-    let dir_hashes = files_in_dir.map(|(d, hashes)| {
-        let mut hasher = dupdir_hash::T1ha2::default();
-        let hashes = hashes.into_iter();
-        hashes.for_each(|h| {
-            // FIXME [NP]: Is this correct? It'll register directories w/ different amounts of
-            // copies of the same file as identical.
-            dupdir_hash::copy_wide(&mut &h.as_bytes()[..], &mut hasher).unwrap();
+    fn close_dir(
+        &mut self,
+        dir: OpenDir<'a, Self::Dir>,
+        parent: Option<&mut OpenDir<'a, Self::Dir>>,
+    ) {
+        let OpenDir { path, contents: mut fingerprint } = dir;
+        let hash = fingerprint.finish(self.algorithm);
+        if let Some(parent) = parent {
+            parent.contents.add_dir(file_name_bytes(path), fingerprint, &hash);
+        }
+        self.dir_hashes.push((hash, path));
+    }
+}
+
+
+
+// ===============
+// === DirWalk ===
+// ===============
+
+/// A single post-order pass over files sorted by path, which summarizes each directory enclosing
+/// them, up to a root, from the files under it.
+///
+/// A directory is opened before its first file is added, and closed once all of its files have
+/// been seen, so that its summary can be folded into its parent's. Only the directories enclosing
+/// the current file are kept open.
+pub(crate) trait DirWalk<'a> {
+    /// The summary of a directory, as it is being accumulated.
+    type Dir;
+
+    fn open_dir(&mut self, path: &'a Path) -> Self::Dir;
+
+    /// Adds a file to the open directories enclosing it, innermost last.
+    fn add_file(
+        &mut self,
+        open_dirs: &mut [OpenDir<'a, Self::Dir>],
+        path: &'a Path,
+        entry: &'a Entry,
+    );
+
+    /// Completes a directory, given its parent unless it is the outermost one.
+    fn close_dir(
+        &mut self,
+        dir: OpenDir<'a, Self::Dir>,
+        parent: Option<&mut OpenDir<'a, Self::Dir>>,
+    );
+
+    /// Walks `entries`, which must be sorted by path, and the directories enclosing them up to
+    /// `root`.
+    fn walk(&mut self, entries: Vec<(&'a PathBuf, &'a Entry)>, root: &Path) {
+        let mut open_dirs = Vec::<OpenDir<'a, Self::Dir>>::new();
+        entries.into_iter().progress().for_each(|(p, e)| {
+            let dir = p.parent().expect("Parent");
+            // Directories which don't contain this file won't contain any later one either.
+            while open_dirs.last().is_some_and(|d| !dir.starts_with(d.path)) {
+                let innermost = open_dirs.pop().expect("Open directory");
+                self.close_dir(innermost, open_dirs.last_mut());
+            }
+            let innermost = open_dirs.last().map(|d| d.path);
+            let new_dirs = dir.ancestors().take_while(|a| Some(*a) != innermost);
+            // Directories above the root are never visited, as they hold more than was scanned.
+            let new_dirs = new_dirs.take_while(|a| a.starts_with(root));
+            let new_dirs = new_dirs.collect::<Vec<_>>();
+            new_dirs.into_iter().rev().for_each(|path| {
+                let contents = self.open_dir(path);
+                open_dirs.push(OpenDir { path, contents });
+            });
+            self.add_file(&mut open_dirs, p, e);
         });
-        let hash = hasher.finish();
-        (hash, d)
-    });
+        while let Some(innermost) = open_dirs.pop() {
+            self.close_dir(innermost, open_dirs.last_mut());
+        }
+    }
+}
 
-    let dir_hashes = dir_hashes.map(|(hash, directory)| {
-        let hash = hex::encode(hash);
-        (hash, directory)
-    });
-    dir_hashes.collect()
+
+
+// ===============
+// === OpenDir ===
+// ===============
+
+/// A directory whose files are still being visited by a [`DirWalk`].
+pub(crate) struct OpenDir<'a, T> {
+    pub(crate) path: &'a Path,
+    pub(crate) contents: T,
+}
+
+
+
+// ===================
+// === Fingerprint ===
+// ===================
+
+/// The part of a directory's contents that its hash depends on, as it is being accumulated.
+///
+/// Fingerprints are built bottom-up: a directory's fingerprint is folded into its parent's once
+/// complete, so each directory is only visited once.
+enum Fingerprint<'a> {
+    /// The sum of the 128-bit hashes of the files' hashes, which doesn't depend on the order they
+    /// are added in, and the number of files.
+    Multiset { sum: u128, count: u64 },
+    /// The files' hashes, deduplicated when the directory is complete.
+    Set(Vec<&'a str>),
+    /// The sum of the 128-bit hashes of the paths relative to the directory and hashes of all
+    /// the files under it, and the number of files. Each file is added to every directory
+    /// enclosing it, so subdirectories add nothing.
+    RelativePaths { sum: u128, count: u64 },
+    /// The names, kinds and hashes of the directory's own entries.
+    Merkle(Vec<(&'a [u8], u8, Cow<'a, str>)>),
+}
+
+// === Main `impl` ===
+
+impl<'a> Fingerprint<'a> {
+    const FILE: u8 = b'f';
+    const DIR: u8 = b'd';
+
+    fn new(mode: DirHashMode) -> Self {
+        match mode {
+            DirHashMode::Multiset => Self::Multiset { sum: 0, count: 0 },
+            DirHashMode::Set => Self::Set(Vec::new()),
+            DirHashMode::RelativePaths => Self::RelativePaths { sum: 0, count: 0 },
+            DirHashMode::Merkle => Self::Merkle(Vec::new()),
+        }
+    }
+
+    /// Adds a file, given its name, or its path relative to the directory under
+    /// [`DirHashMode::RelativePaths`].
+    fn add_file(&mut self, name: &'a [u8], hash: &'a str, algorithm: Algorithm) {
+        match self {
+            Self::Multiset { sum, count } => {
+                *sum = sum.wrapping_add(hash_128(algorithm, &[hash.as_bytes()]));
+                *count += 1;
+            },
+            Self::Set(hashes) => hashes.push(hash),
+            Self::RelativePaths { sum, count } => {
+                *sum = sum.wrapping_add(entry_hash_128(algorithm, name, Self::FILE, hash));
+                *count += 1;
+            },
+            Self::Merkle(entries) => entries.push((name, Self::FILE, Cow::Borrowed(hash))),
+        }
+    }
+
+    /// Adds a complete subdirectory, given its fingerprint and the hash it finished with.
+    fn add_dir(&mut self, name: &'a [u8], dir: Self, hash: &str) {
+        match (self, dir) {
+            (Self::Multiset { sum, count }, Self::Multiset { sum: s, count: c }) => {
+                *sum = sum.wrapping_add(s);
+                *count += c;
+            },
+            (Self::Set(hashes), Self::Set(h)) => hashes.extend(h),
+            (Self::RelativePaths { .. }, Self::RelativePaths { .. }) => {},
+            (Self::Merkle(entries), Self::Merkle(_)) => {
+                entries.push((name, Self::DIR, Cow::Owned(hash.to_string())));
+            },
+            _ => unreachable!("Fingerprints of a tree share a mode"),
+        }
+    }
+
+    /// Returns the hash of the directory, leaving the fingerprint ready to be added to the
+    /// parent directory with [`add_dir`](Self::add_dir).
+    fn finish(&mut self, algorithm: Algorithm) -> String {
+        let mut hasher = algorithm.hasher();
+        match self {
+            Self::Multiset { sum, count } | Self::RelativePaths { sum, count } => {
+                hasher.update(&sum.to_le_bytes());
+                hasher.update(&count.to_le_bytes());
+            },
+            Self::Set(hashes) => {
+                hashes.sort_unstable();
+                hashes.dedup();
+                hashes.iter().for_each(|h| hasher.update(h.as_bytes()));
+            },
+            Self::Merkle(entries) => {
+                entries.sort_unstable();
+                entries.iter().for_each(|(name, kind, hash)| {
+                    hasher.update(&[*kind]);
+                    hasher.update(name);
+                    // Names never contain NUL, so it separates them from the fixed-length hashes.
+                    hasher.update(b"\0");
+                    hasher.update(hash.as_bytes());
+                });
+            },
+        }
+        hex::encode(hasher.finish())
+    }
+}
+
+/// Hashes `parts` to 128 bits, for fingerprints which add up hashes. Algorithms with digests
+/// shorter than 16 bytes hash `parts` again behind increasing seeds until the bits are filled.
+fn hash_128(algorithm: Algorithm, parts: &[&[u8]]) -> u128 {
+    let mut bytes = Vec::with_capacity(16);
+    let mut seed = 0_u8;
+    while bytes.len() < 16 {
+        let mut hasher = algorithm.hasher();
+        hasher.update(&[seed]);
+        parts.iter().for_each(|p| hasher.update(p));
+        bytes.extend(hasher.finish());
+        seed += 1;
+    }
+    let bytes = bytes[..16].try_into().expect("16 bytes");
+    u128::from_le_bytes(bytes)
+}
+
+fn entry_hash_128(algorithm: Algorithm, name: &[u8], kind: u8, hash: &str) -> u128 {
+    hash_128(algorithm, &[&[kind], name, b"\0", hash.as_bytes()])
 }
 
 
@@ -306,15 +1842,26 @@ pub fn dir_hashes(
 // === dup_dirs ===
 // ================
 
-fn dup_dirs(dir_hashes: &[(String, &Path)]) -> Vec<String> {
+/// A directory whose contents are duplicated elsewhere under the same root.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DupDir {
+    /// The hash shared by all copies of the directory, told apart from those of other contents
+    /// by [`Verified`] under [`Options::verify`].
+    pub hash: String,
+    pub path: PathBuf,
+    /// Whether the directory's files are hardlinks to the same inodes as another copy, so that
+    /// removing it would not free any space.
+    pub hardlinked: bool,
+}
+
+/// Groups the directories by hash, keeping only those with at least one duplicate. A directory is
+/// left out when one of its ancestors has the same hash, as it would be reported with it.
+pub fn dup_dirs(dir_hashes: &[(String, &Path)]) -> Vec<DupDir> {
     // Read the mapping of hash -> dir
     eprintln!("Reading (hash -> dir) mapping");
     let dir_hashes = dir_hashes.iter();
     let dir_hashes = dir_hashes.progress();
-    let dir_hashes = dir_hashes.map(|(h, d)| {
-        let d = path_to_string(d);
-        (h.as_str(), d)
-    });
+    let dir_hashes = dir_hashes.map(|(h, d)| (h.as_str(), *d));
     // FIXME [NP]: Remove this collect
     let dir_hashes = dir_hashes.collect::<Vec<_>>();
 
@@ -330,29 +1877,28 @@ fn dup_dirs(dir_hashes: &[(String, &Path)]) -> Vec<String> {
         .progress()
         .partition::<HashMap<_, _>, _>(|(_, ds)| ds.len() == 1);
 
-    // Among the duplicate directories, sort them by the length of their path, shortest first.
+    // Among the duplicate directories, sort them by path. Paths compare component-wise, so each
+    // directory is directly followed by its subdirectories.
     let dup = dup
         .into_iter()
         .progress()
         .map(|(h, mut ds)| {
-            ds.sort_by_key(|d| d.len());
+            ds.sort_unstable();
             (h, ds)
         })
         .collect::<HashMap<_, _>>();
 
-    // If a directory is a subdirectory of another directory with the same hash, remove it.
+    // If a directory is a subdirectory of another directory with the same hash, remove it. As the
+    // subdirectories of a kept directory follow it, only the last kept directory is compared.
     let dup = dup
         .into_iter()
         .progress()
         .map(|(h, ds)| {
-            let mut ds = ds.into_iter();
-            let mut ds2 = vec![ds.next().unwrap()];
+            let mut ds2 = Vec::<&Path>::new();
             for d in ds {
-                let ancestor = ds2.iter().find(|d2| d.starts_with(*d2));
-                if ancestor.is_none() {
+                let nested = ds2.last().is_some_and(|ancestor| d.starts_with(ancestor));
+                if !nested {
                     ds2.push(d);
-                    //} else {
-                    //    eprintln!("Removing {d:?} because of {ancestor:?}");
                 }
             }
             (h, ds2)
@@ -376,24 +1922,169 @@ fn dup_dirs(dir_hashes: &[(String, &Path)]) -> Vec<String> {
         .collect::<Vec<_>>();
 
     // Sort the mapping by dir name.
-    sort_by_key_ref(&mut dup_dirs, |(_, d)| d.as_str());
+    sort_by_key_ref(&mut dup_dirs, |(_, d)| path_to_bytes(d));
 
-    // Turn this into a list of strings.
-    eprintln!("Convert vec<(hash, dir)> to vec<str>");
+    // Turn this into a list of results.
+    eprintln!("Convert vec<(hash, dir)> to vec<DupDir>");
     #[cfg(feature = "rayon")]
-    let dup_dirs = dup_dirs.par_iter();
+    let dup_dirs = dup_dirs.into_par_iter();
     #[cfg(not(feature = "rayon"))]
-    let dup_dirs = dup_dirs.iter();
+    let dup_dirs = dup_dirs.into_iter();
     dup_dirs
         .progress()
-        .inspect(|(h, d)| {
-            assert!(!h.contains(UNIQUE_SEPARATOR));
-            assert!(!d.contains(UNIQUE_SEPARATOR));
+        .map(|(h, d)| {
+            let hash = h.to_string();
+            let path = d.to_path_buf();
+            DupDir { hash, path, hardlinked: false }
         })
-        .map(|(h, d)| [*h, d].join(UNIQUE_SEPARATOR))
         .collect::<Vec<_>>()
 }
 
+/// Keeps only the maximal groups of duplicate directories, dropping any group whose members all lie
+/// inside the members of a single kept group. When `/a` and `/b` are duplicates, `/a/x` and `/b/x`
+/// are then left out, while a group of `/a/x`, `/b/x` and `/c` is kept, as is a group of `/a/x`,
+/// `/b/x`, `/c/y` and `/d/y` when `/c` and `/d` are duplicates of each other but not of `/a`.
+pub fn maximal_dup_dirs(dup_dirs: Vec<DupDir>) -> Vec<DupDir> {
+    let mut groups = HashMap::<&str, Vec<&Path>>::new();
+    dup_dirs.iter().for_each(|d| groups.entry(&d.hash).or_default().push(&d.path));
+
+    // A group can only lie inside groups with a shallower member, so those are decided first.
+    let depth = |ds: &[&Path]| ds.iter().map(|d| d.components().count()).min();
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_by_cached_key(|(_, ds)| depth(ds));
+    // The members of the kept groups, with the index of their group.
+    let mut kept_dirs = HashMap::<&Path, usize>::new();
+    let mut kept = HashSet::new();
+    for (hash, ds) in groups {
+        let enclosing = |d: &&Path| {
+            let groups = d.ancestors().skip(1).filter_map(|a| kept_dirs.get(a).copied());
+            groups.collect::<HashSet<_>>()
+        };
+        let mut enclosing = ds.iter().map(enclosing);
+        let first = enclosing.next().unwrap_or_default();
+        let common = enclosing.fold(first, |common, e| &common & &e);
+        if common.is_empty() {
+            let group = kept.len();
+            kept_dirs.extend(ds.iter().map(|d| (*d, group)));
+            kept.insert(hash.to_string());
+        }
+    }
+
+    let dup_dirs = dup_dirs.iter().filter(|d| kept.contains(&d.hash));
+    dup_dirs.cloned().collect()
+}
+
+/// Marks the duplicate directories which are only copies of another through hardlinks, by the
+/// inodes of the files under them.
+fn mark_hardlinks(state: &State, root: &Path, dup_dirs: &mut [DupDir], options: &Options) {
+    let mut groups = HashMap::<_, Vec<_>>::new();
+    dup_dirs.iter().enumerate().for_each(|(i, d)| {
+        groups.entry(d.hash.clone()).or_default().push(i);
+    });
+    groups.into_values().for_each(|group| {
+        let inodes = group.iter().map(|i| dir_inodes(state, root, &dup_dirs[*i].path, options));
+        let (hardlinked, _) = hardlinked_copies(&inodes.collect::<Vec<_>>());
+        group.into_iter().zip(hardlinked).for_each(|(i, hardlinked)| {
+            dup_dirs[i].hardlinked = hardlinked;
+        });
+    });
+}
+
+/// Marks the copies with the same contents which are hardlinks, given the inodes of each copy, or
+/// `None` if unknown. Returns the marks and the number of actual copies.
+///
+/// A copy is hardlinked if its inodes are those of a copy before it. If that leaves a single
+/// actual copy, it is marked as well, since none of the copies can be removed to free space.
+fn hardlinked_copies<T: Eq + Hash>(inodes: &[Option<T>]) -> (Vec<bool>, usize) {
+    let mut seen = HashSet::new();
+    let linked = inodes.iter().map(|i| i.as_ref().is_some_and(|i| !seen.insert(i)));
+    let linked = linked.collect::<Vec<_>>();
+    let copies = linked.iter().filter(|l| !**l).count();
+    let hardlinked = linked.into_iter().map(|l| l || copies == 1).collect();
+    (hardlinked, copies)
+}
+
+/// Returns the inodes of the files of `root` under `dir` other than junk files, or `None` if some
+/// file's inode is unknown.
+fn dir_inodes(
+    state: &State,
+    root: &Path,
+    dir: &Path,
+    options: &Options,
+) -> Option<BTreeSet<(u64, u64)>> {
+    let entries = state.dir_entries(root, dir);
+    let entries = entries.filter(|(p, _)| !options.is_junk_file(p));
+    entries.map(|(_, e)| file_inode(e)).collect()
+}
+
+/// Returns the device and inode of a file, or `None` if its inode is unknown.
+fn file_inode(entry: &Entry) -> Option<(u64, u64)> {
+    let Metadata { device, inode, .. } = entry.metadata;
+    entry.metadata.identifies_inode().then_some((device, inode))
+}
+
+
+
+
+// =================
+// === dup_files ===
+// =================
+
+/// A file whose contents are duplicated elsewhere under the same root.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DupFile {
+    /// The hash shared by all copies of the file, told apart from those of other contents
+    /// by [`Verified`] under [`Options::verify`].
+    pub hash: String,
+    pub path: PathBuf,
+    /// The size of each copy of the file.
+    pub size: u64,
+    /// The bytes taken by the copies of the file beyond the first, i.e. the space freed by keeping
+    /// a single copy. Hardlinks to the same inode are a single copy.
+    pub wasted: u64,
+    /// Whether the file is a hardlink to the same inode as another copy, so that removing it would
+    /// not free any space.
+    pub hardlinked: bool,
+}
+
+/// Groups the files under `root` by hash, keeping only those with at least one duplicate, sorted
+/// by path.
+///
+/// Empty files and [`Options::junk_files`] are left out. Files are grouped by their hash in
+/// `verified`, as returned by [`verify_files`]. Each file is marked as hardlinked by the inode of
+/// the file itself.
+pub fn dup_files(
+    state: &State,
+    root: &Path,
+    verified: &Verified,
+    options: &Options,
+) -> Vec<DupFile> {
+    eprintln!("Finding duplicate files...");
+    let entries = state.root_entries(root);
+    let entries = entries.filter(|(p, e)| options.is_compared(p, e));
+    let mut groups = HashMap::<&str, Vec<(&PathBuf, &Entry)>>::new();
+    entries.for_each(|(p, e)| groups.entry(verified.hash(p, e)).or_default().push((p, e)));
+
+    let groups = groups.into_values().filter(|files| files.len() > 1);
+    let dup_files = groups.flat_map(|files| {
+        let inodes = files.iter().map(|(_, e)| file_inode(e)).collect::<Vec<_>>();
+        let (hardlinked, copies) = hardlinked_copies(&inodes);
+        files.into_iter().zip(hardlinked).map(move |((path, entry), hardlinked)| {
+            let size = entry.metadata.size;
+            DupFile {
+                hash: verified.hash(path, entry).to_string(),
+                path: path.clone(),
+                size,
+                wasted: size * (copies as u64 - 1),
+                hardlinked,
+            }
+        })
+    });
+    let mut dup_files = dup_files.collect::<Vec<_>>();
+    sort_by_key_ref(&mut dup_files, |d| path_to_bytes(&d.path));
+    dup_files
+}
+
 // NOTE [NP]: `Vec::sort_by_key` doesn't work when the key is a `String` (since we can't return
 // references from closures without GATs). This is a workaround for that.
 // See: https://stackoverflow.com/a/47127500
@@ -0,0 +1,76 @@
+use crate::prelude::*;
+
+use core::str;
+use std::io;
+
+
+
+// =================
+// === Constants ===
+// =================
+
+/// Separates the fields of a record. Hashes never contain it, so a record can always be split at
+/// its first separator, even if the path contains it too.
+pub const FIELD_SEPARATOR: &str = ";";
+
+
+
+// ====================
+// === OutputFormat ===
+// ====================
+
+/// How `<hash>;<path>` records are written.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[must_use]
+pub enum OutputFormat {
+    /// One record per line, with paths escaped by [`escape_path`].
+    #[default]
+    Text,
+    /// One record per line, with paths quoted by [`quote_path`] for pasting into a shell.
+    Quoted,
+    /// Records terminated by NUL bytes, with paths written as their raw bytes.
+    ///
+    /// NUL is the only byte which can't appear in a path, so this is the most robust format for
+    /// other programs to parse, e.g. with `xargs -0`.
+    Nul,
+}
+
+// === Main `impl` ===
+
+impl OutputFormat {
+    /// Writes a single record.
+    pub fn write_record(
+        self,
+        writer: &mut dyn io::Write,
+        hash: &str,
+        path: &Path,
+    ) -> io::Result<()> {
+        debug_assert!(!hash.contains(FIELD_SEPARATOR));
+        writer.write_all(hash.as_bytes())?;
+        writer.write_all(FIELD_SEPARATOR.as_bytes())?;
+        match self {
+            Self::Text => writeln!(writer, "{}", escape_path(path)),
+            Self::Quoted => writeln!(writer, "{}", quote_path(path)),
+            Self::Nul => {
+                writer.write_all(path_to_bytes(path))?;
+                writer.write_all(b"\0")
+            },
+        }
+    }
+}
+
+// === Trait `impl`s ===
+
+impl str::FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format = match s {
+            "text" => Self::Text,
+            "quoted" => Self::Quoted,
+            "nul" => Self::Nul,
+            _ => Err(format!("Invalid output format: {s}"))?,
+        };
+        Ok(format)
+    }
+}
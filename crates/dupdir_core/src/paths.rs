@@ -0,0 +1,251 @@
+use crate::prelude::*;
+
+use core::fmt::Write as _;
+use core::str;
+use serde::de;
+use serde::Deserializer;
+use serde::Serializer;
+use std::borrow::Cow;
+
+
+
+// ===============
+// === Helpers ===
+// ===============
+
+/// Checks that a path found during a scan is supported.
+///
+/// Any name the file system accepts is supported, including ones with control characters or
+/// surrounding whitespace; output formats escape or quote these as needed.
+#[inline]
+pub fn check_path_rules(p: impl AsRef<Path>) -> Result<()> {
+    let path = p.as_ref();
+    match path.as_os_str().is_empty() {
+        true => Err(Error::InvalidPath { path: path.to_path_buf(), reason: "Empty path" }),
+        false => Ok(()),
+    }
+}
+
+#[inline]
+pub fn path_to_str(p: &impl AsRef<Path>) -> Result<&str> {
+    let p = p.as_ref();
+    let reason = "Path should be valid UTF-8";
+    p.to_str().ok_or_else(|| Error::InvalidPath { path: p.to_path_buf(), reason })
+}
+
+#[inline]
+pub fn path_to_string(p: impl AsRef<Path>) -> Result<String> {
+    let p = path_to_str(&p)?;
+    Ok(p.to_string())
+}
+
+/// Returns the raw bytes of a path.
+///
+/// On Unix these are the bytes the OS uses for the path, which need not be valid UTF-8.
+#[inline]
+pub fn path_to_bytes(p: &Path) -> &[u8] {
+    p.as_os_str().as_encoded_bytes()
+}
+
+/// The inverse of [`path_to_bytes`].
+///
+/// On platforms other than Unix, paths are only stored as bytes when they are valid UTF-8, so
+/// invalid sequences are replaced rather than rejected.
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt as _;
+        PathBuf::from(std::ffi::OsString::from_vec(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        let path = String::from_utf8_lossy(&bytes);
+        PathBuf::from(path.into_owned())
+    }
+}
+
+/// Formats a path for line-based text output.
+///
+/// Backslashes and control characters are escaped C-style (`\\`, `\n`, `\r`, `\t` and `\xNN`),
+/// as are bytes which aren't part of a valid UTF-8 sequence, so the result never spans more than
+/// one line and can be unescaped back to the original bytes.
+pub fn escape_path(p: &Path) -> Cow<'_, str> {
+    let needs_escape = |c: char| c == '\\' || c.is_control();
+    if let Some(p) = p.to_str() {
+        if !p.contains(needs_escape) {
+            return Cow::Borrowed(p);
+        }
+    }
+    let mut escaped = String::new();
+    for_each_utf8_chunk(path_to_bytes(p), |valid, invalid| {
+        valid.chars().for_each(|c| match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => {
+                let mut buf = [0; 4];
+                let c = c.encode_utf8(&mut buf).bytes();
+                c.for_each(|b| push_hex(&mut escaped, b));
+            },
+            c => escaped.push(c),
+        });
+        invalid.iter().for_each(|b| push_hex(&mut escaped, *b));
+    });
+    Cow::Owned(escaped)
+}
+
+/// Quotes a path for pasting into a POSIX shell.
+///
+/// Paths made only of characters which are never special to the shell are left as-is. Others are
+/// wrapped in single quotes, unless they contain control characters or invalid UTF-8, in which
+/// case they are written as a `$'...'` string using the escapes of [`escape_path`].
+pub fn quote_path(p: &Path) -> Cow<'_, str> {
+    let is_plain = |c: char| c.is_alphanumeric() || "/._-+,:@%=".contains(c);
+    match p.to_str() {
+        Some(s) if !s.is_empty() && s.chars().all(is_plain) => Cow::Borrowed(s),
+        Some(s) if !s.contains(|c: char| c.is_control()) => {
+            let s = s.replace('\'', "'\\''");
+            Cow::Owned(format!("'{s}'"))
+        },
+        _ => {
+            let s = escape_path(p).replace('\'', "\\'");
+            Cow::Owned(format!("$'{s}'"))
+        },
+    }
+}
+
+fn push_hex(s: &mut String, b: u8) {
+    write!(s, "\\x{b:02x}").expect("Write to string");
+}
+
+/// Calls `f` with each run of valid UTF-8 in `bytes`, followed by the invalid bytes after it.
+fn for_each_utf8_chunk(mut bytes: &[u8], mut f: impl FnMut(&str, &[u8])) {
+    while !bytes.is_empty() {
+        match str::from_utf8(bytes) {
+            Ok(valid) => {
+                f(valid, &[]);
+                break;
+            },
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                let valid = str::from_utf8(valid).expect("Valid UTF-8");
+                let invalid_len = e.error_len().unwrap_or(rest.len());
+                let (invalid, rest) = rest.split_at(invalid_len);
+                f(valid, invalid);
+                bytes = rest;
+            },
+        }
+    }
+}
+
+
+
+// ================
+// === Encoding ===
+// ================
+
+/// Encodes a path as a string, losslessly.
+///
+/// Valid UTF-8 is kept as-is except for `%`, which is written as `%25`. Bytes which aren't part
+/// of a valid UTF-8 sequence are written as `%NN`, so paths from e.g. Latin-1 file systems
+/// survive a round trip through a JSON state file.
+pub fn encode_path(p: &Path) -> Cow<'_, str> {
+    if let Some(p) = p.to_str() {
+        if !p.contains('%') {
+            return Cow::Borrowed(p);
+        }
+    }
+    let mut encoded = String::new();
+    for_each_utf8_chunk(path_to_bytes(p), |valid, invalid| {
+        encoded.push_str(&valid.replace('%', "%25"));
+        invalid.iter().for_each(|b| write!(encoded, "%{b:02X}").expect("Write to string"));
+    });
+    Cow::Owned(encoded)
+}
+
+/// The inverse of [`encode_path`], or `None` if `encoded` contains a malformed `%` escape.
+pub fn decode_path(encoded: &str) -> Option<PathBuf> {
+    if !encoded.contains('%') {
+        return Some(PathBuf::from(encoded));
+    }
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+        let escape = rest.get(..2)?;
+        let escape = str::from_utf8(escape).ok()?;
+        let b = u8::from_str_radix(escape, 16).ok()?;
+        bytes.push(b);
+        rest = &rest[2..];
+    }
+    Some(path_from_bytes(bytes))
+}
+
+/// A path serialized with [`encode_path`].
+struct Encoded<'a>(&'a Path);
+
+impl Serialize for Encoded<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_path(self.0))
+    }
+}
+
+/// A path deserialized with [`decode_path`].
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Decoded(PathBuf);
+
+impl<'de> Deserialize<'de> for Decoded {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let encoded = Cow::<str>::deserialize(deserializer)?;
+        let path = decode_path(&encoded);
+        let path = path.ok_or_else(|| de::Error::custom(format!("Invalid path: {encoded:?}")))?;
+        Ok(Self(path))
+    }
+}
+
+/// Serde helpers for maps keyed by paths, for use with `#[serde(with = "...")]`.
+pub(crate) mod map {
+    use super::*;
+
+    pub(crate) fn serialize<S, V>(map: &BTreeMap<PathBuf, V>, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        V: Serialize,
+    {
+        s.collect_map(map.iter().map(|(p, v)| (Encoded(p), v)))
+    }
+
+    pub(crate) fn deserialize<'de, D, V>(d: D) -> Result<BTreeMap<PathBuf, V>, D::Error>
+    where
+        D: Deserializer<'de>,
+        V: Deserialize<'de>,
+    {
+        let map = BTreeMap::<Decoded, V>::deserialize(d)?;
+        Ok(map.into_iter().map(|(p, v)| (p.0, v)).collect())
+    }
+}
+
+/// Serde helpers for sets of paths, for use with `#[serde(with = "...")]`.
+pub(crate) mod set {
+    use super::*;
+
+    pub(crate) fn serialize<S>(set: &BTreeSet<PathBuf>, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.collect_seq(set.iter().map(|p| Encoded(p)))
+    }
+
+    pub(crate) fn deserialize<'de, D>(d: D) -> Result<BTreeSet<PathBuf>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let set = Vec::<Decoded>::deserialize(d)?;
+        Ok(set.into_iter().map(|p| p.0).collect())
+    }
+}
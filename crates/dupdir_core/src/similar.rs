@@ -0,0 +1,478 @@
+use crate::prelude::*;
+
+use core::ops;
+use core::str;
+use crate::DirWalk;
+use crate::OpenDir;
+use indicatif::ProgressIterator as _;
+use std::collections::HashSet;
+
+
+
+// =================
+// === Constants ===
+// =================
+
+/// The number of directories above which a hash is too common to suggest [`similar_dirs`]
+/// candidates, as comparing every pair of them would cost more than it reveals. Only the deepest
+/// directories holding a hash count, since the others are its ancestors and never compared with
+/// them.
+const MAX_DIRS_PER_HASH: usize = 100;
+
+
+
+// ==================
+// === Similarity ===
+// ==================
+
+/// How the similarity of two directories is measured from the distinct file contents under them.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Similarity {
+    /// The contents found in both directories, over the contents found in either (the Jaccard
+    /// index).
+    #[default]
+    Jaccard,
+    /// The contents found in both directories, over the contents of the smaller one, so that a
+    /// directory whose files were all copied into a larger one is fully similar to it.
+    Containment,
+}
+
+// === Main `impl` ===
+
+impl Similarity {
+    /// Returns the similarity of directories with `left` and `right` distinct contents, `shared`
+    /// of which are in both.
+    fn of(self, left: usize, right: usize, shared: usize) -> f64 {
+        let total = match self {
+            Self::Jaccard => left + right - shared,
+            Self::Containment => left.min(right),
+        };
+        shared as f64 / total as f64
+    }
+
+    /// Returns the highest similarity directories with `left` and `right` distinct contents can
+    /// have, reached when all of the smaller one's contents are shared.
+    fn bound(self, left: usize, right: usize) -> f64 {
+        self.of(left, right, left.min(right))
+    }
+}
+
+// === Trait `impl`s ===
+
+impl str::FromStr for Similarity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let similarity = match s {
+            "jaccard" => Self::Jaccard,
+            "containment" => Self::Containment,
+            _ => Err(format!("Invalid similarity measure: {s}"))?,
+        };
+        Ok(similarity)
+    }
+}
+
+
+
+// ===============
+// === Content ===
+// ===============
+
+/// An amount of distinct file contents, i.e. of files with different hashes.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Content {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+// === Main `impl` ===
+
+impl Content {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+// === Trait `impl`s ===
+
+impl ops::Sub for Content {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        let files = self.files - other.files;
+        let bytes = self.bytes - other.bytes;
+        Self { files, bytes }
+    }
+}
+
+
+
+// ===================
+// === SimilarDirs ===
+// ===================
+
+/// Two directories which hold mostly, but not exactly, the same file contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimilarDirs {
+    /// The first of the two directories, by path.
+    pub left: PathBuf,
+    pub right: PathBuf,
+    /// The similarity of the directories, between `0` and `1`, by [`Options::similarity`].
+    pub similarity: f64,
+    /// The contents found in both directories.
+    pub shared: Content,
+    /// The contents only found in [`left`](Self::left).
+    pub left_only: Content,
+    /// The contents only found in [`right`](Self::right).
+    pub right_only: Content,
+}
+
+
+
+// ====================
+// === similar_dirs ===
+// ====================
+
+/// Finds the pairs of directories under `root` whose similarity is at least
+/// [`Options::min_similarity`], most similar first.
+///
+/// Directories are compared by the distinct hashes of the non-empty files under them, leaving out
+/// [`Options::junk_files`], so names, layout and the number of copies of a file don't matter. A
+/// directory is never compared with its own subdirectories, and pairs holding exactly the same
+/// contents are left to [`dup_dirs`](crate::dup_dirs). The only subdirectory of a directory with
+/// no files of its own is represented by that directory, as they hold the same contents.
+///
+/// Candidate pairs are found through an index from each file hash to the directories holding
+/// it, so only directories sharing some contents are ever compared. Hashes held by more than
+/// [`MAX_DIRS_PER_HASH`] directories are left out of the index, though they still count towards
+/// the similarity of the pairs found through other hashes. Pairs which can't reach the minimum
+/// similarity given the sizes of the two directories are skipped without counting their shared
+/// contents.
+pub fn similar_dirs(state: &State, root: &Path, options: &Options) -> Vec<SimilarDirs> {
+    let DirIndex { dirs, sizes, holders, .. } = DirIndex::new(state, root, options);
+    let redundant = dirs.iter().map(|d| d.parent.is_some_and(|p| dirs[p].is_wrapper()));
+    let redundant = redundant.collect::<Vec<_>>();
+    let index = postings(&dirs, sizes.len(), |d| !redundant[d]);
+    let common = holders.iter().filter(|h| **h > MAX_DIRS_PER_HASH).count();
+    if common > 0 {
+        eprintln!("Skipping {common} contents found in more than {MAX_DIRS_PER_HASH} directories");
+    }
+    let index = index.into_iter().zip(holders).filter(|(_, h)| *h <= MAX_DIRS_PER_HASH);
+    let index = index.map(|(ds, _)| ds).collect::<Vec<_>>();
+
+    // Find the pairs of directories sharing some contents.
+    eprintln!("Comparing directories...");
+    let similarity = options.similarity;
+    let mut pairs = HashSet::<(usize, usize)>::new();
+    index.iter().progress().for_each(|ds| {
+        ds.iter().enumerate().for_each(|(i, a)| {
+            ds[i + 1..].iter().for_each(|b| {
+                let (a_path, b_path) = (dirs[*a].path, dirs[*b].path);
+                let nested = a_path.starts_with(b_path) || b_path.starts_with(a_path);
+                let bound = similarity.bound(dirs[*a].total.files, dirs[*b].total.files);
+                if !nested && bound >= options.min_similarity {
+                    pairs.insert((*a, *b));
+                }
+            });
+        });
+    });
+
+    let similar = pairs.into_iter().filter_map(|(a, b)| {
+        let (a, b) = if dirs[a].path < dirs[b].path { (a, b) } else { (b, a) };
+        let shared = dirs[a].shared(&dirs[b], &sizes);
+        let (left, right) = (dirs[a].total, dirs[b].total);
+        let identical = shared == left && shared == right;
+        let similarity = similarity.of(left.files, right.files, shared.files);
+        let similar = similarity >= options.min_similarity && !identical;
+        similar.then(|| SimilarDirs {
+            left: dirs[a].path.to_path_buf(),
+            right: dirs[b].path.to_path_buf(),
+            similarity,
+            shared,
+            left_only: left - shared,
+            right_only: right - shared,
+        })
+    });
+    let mut similar = similar.collect::<Vec<_>>();
+    similar.sort_by(|a, b| {
+        let similarity = b.similarity.total_cmp(&a.similarity);
+        similarity.then_with(|| (&a.left, &a.right).cmp(&(&b.left, &b.right)))
+    });
+    similar
+}
+
+
+
+// ======================
+// === contained_dirs ===
+// ======================
+
+/// A directory whose contents are all, or nearly all, found in one or two other directories.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ContainedDir {
+    pub path: PathBuf,
+    /// The directories holding the contents of [`path`](Self::path), which is a subset of their
+    /// union. There are two only if neither holds all the contents found in both.
+    pub containers: Vec<PathBuf>,
+    /// The files under [`path`](Self::path) whose contents are in none of the containers, sorted.
+    /// Empty if the directory is fully contained.
+    pub missing: Vec<PathBuf>,
+}
+
+/// Finds the directories under `root` whose contents are also found elsewhere under `root`, in
+/// one directory or in the union of two, along with those for which at least
+/// [`Options::min_similarity`] of their distinct contents are, sorted by path.
+///
+/// Directories are compared by the distinct hashes of the non-empty files under them, leaving
+/// out [`Options::junk_files`], as in [`similar_dirs`]. A directory is never contained in its own
+/// ancestors or subdirectories. Among directories with the same contents, only the later ones are
+/// reported as contained in the first, so that a copy is always kept. A directory is left out
+/// when one of its ancestors is reported, as its contents are then accounted for.
+///
+/// Each report holds on its own: removing several reported directories may remove the last copy
+/// of something, as a container may itself be reported as contained elsewhere.
+///
+/// Directories holding nothing but empty files have no contents to find elsewhere, so they are
+/// never reported.
+pub fn contained_dirs(state: &State, root: &Path, options: &Options) -> Vec<ContainedDir> {
+    let DirIndex { dirs, sizes, hash_ids, .. } = DirIndex::new(state, root, options);
+    let index = postings(&dirs, sizes.len(), |_| true);
+    let mut order = (0..dirs.len()).collect::<Vec<_>>();
+    order.sort_unstable_by_key(|d| dirs[*d].path);
+
+    eprintln!("Finding contained directories...");
+    let mut contained = Vec::<ContainedDir>::new();
+    order.into_iter().progress().for_each(|a| {
+        let dir = &dirs[a];
+        let reported = contained.last().is_some_and(|c| dir.path.starts_with(&c.path));
+        if reported {
+            return;
+        }
+        // The best container is the one holding the most contents, then the smallest one.
+        let best_container = |hashes: &[usize]| {
+            let mut shared = HashMap::<usize, usize>::new();
+            let candidates = hashes.iter().flat_map(|h| &index[*h]);
+            candidates.for_each(|b| *shared.entry(*b).or_default() += 1);
+            let candidates = shared.into_iter().filter(|(b, _)| {
+                let b_path = dirs[*b].path;
+                let nested = dir.path.starts_with(b_path) || b_path.starts_with(dir.path);
+                let later_copy = b_path > dir.path && dirs[*b].hashes == dir.hashes;
+                !(nested || later_copy)
+            });
+            let key = |(b, count): &(usize, usize)| {
+                (usize::MAX - count, dirs[*b].total.files, dirs[*b].path)
+            };
+            candidates.min_by_key(key).map(|(b, _)| b)
+        };
+        let Some(first) = best_container(&dir.hashes) else {
+            return;
+        };
+        let uncovered = |containers: &[usize], hashes: &[usize]| {
+            let hashes = hashes.iter().copied();
+            let hashes = hashes.filter(|h| containers.iter().all(|c| !dirs[*c].holds(*h)));
+            hashes.collect::<Vec<_>>()
+        };
+        let mut containers = vec![first];
+        let mut missing = uncovered(&containers, &dir.hashes);
+        if !missing.is_empty() {
+            if let Some(second) = best_container(&missing) {
+                containers.push(second);
+                missing = uncovered(&containers, &missing);
+            }
+        }
+        let found = 1.0 - missing.len() as f64 / dir.hashes.len() as f64;
+        if found < options.min_similarity {
+            return;
+        }
+
+        let files = state.dir_entries(root, dir.path).filter(|(p, e)| options.is_compared(p, e));
+        let is_missing = |e: &Entry| missing.binary_search(&hash_ids[e.hash.as_str()]).is_ok();
+        let files = files.filter(|(_, e)| is_missing(e));
+        let missing = files.map(|(p, _)| p.clone()).collect();
+        let containers = containers.into_iter().map(|c| dirs[c].path.to_path_buf()).collect();
+        let path = dir.path.to_path_buf();
+        contained.push(ContainedDir { path, containers, missing });
+    });
+    contained
+}
+
+
+
+// ================
+// === DirIndex ===
+// ================
+
+/// The distinct contents of each directory under a root, by which [`similar_dirs`] and
+/// [`contained_dirs`] compare them.
+struct DirIndex<'a> {
+    dirs: Vec<IndexedDir<'a>>,
+    /// The size of the files with each hash, by the number of the hash.
+    sizes: Vec<u64>,
+    /// The number of directories holding each hash none of whose subdirectories hold it, by the
+    /// number of the hash.
+    holders: Vec<usize>,
+    hash_ids: HashMap<&'a str, usize>,
+}
+
+// === Main `impl` ===
+
+impl<'a> DirIndex<'a> {
+    /// Indexes the directories under `root`, which is left out under [`Options::exclude_root`], by
+    /// the files [`Options::is_compared`] holds for.
+    fn new(state: &'a State, root: &Path, options: &Options) -> Self {
+        eprintln!("Indexing directory contents...");
+        let entries = state.root_entries(root);
+        let entries = entries.filter(|(p, e)| options.is_compared(p, e));
+        let entries = entries.collect::<Vec<_>>();
+        let mut index = Self {
+            dirs: Vec::new(),
+            sizes: Vec::new(),
+            holders: Vec::new(),
+            hash_ids: HashMap::new(),
+        };
+        index.walk(entries, root);
+        if options.exclude_root {
+            index.dirs.retain(|d| d.path != root);
+        }
+
+        let dirs = index.dirs.iter().enumerate();
+        let dir_ids = dirs.map(|(i, d)| (d.path, i)).collect::<HashMap<_, _>>();
+        index.dirs.iter_mut().for_each(|d| {
+            d.parent = d.path.parent().and_then(|p| dir_ids.get(p)).copied();
+        });
+        index
+    }
+
+    /// Returns the number of `hash`, numbering it if it is new.
+    fn hash_id(&mut self, hash: &'a str, size: u64) -> usize {
+        let Self { sizes, holders, hash_ids, .. } = self;
+        *hash_ids.entry(hash).or_insert_with(|| {
+            sizes.push(size);
+            holders.push(0);
+            sizes.len() - 1
+        })
+    }
+}
+
+// === Trait `impl`s ===
+
+impl<'a> DirWalk<'a> for DirIndex<'a> {
+    type Dir = IndexedDir<'a>;
+
+    fn open_dir(&mut self, path: &'a Path) -> Self::Dir {
+        IndexedDir::new(path)
+    }
+
+    fn add_file(
+        &mut self,
+        open_dirs: &mut [OpenDir<'a, Self::Dir>],
+        _path: &'a Path,
+        entry: &'a Entry,
+    ) {
+        let id = self.hash_id(&entry.hash, entry.metadata.size);
+        // A root which is a file has no directory to add it to.
+        if let Some(dir) = open_dirs.last_mut() {
+            dir.contents.hashes.push(id);
+            dir.contents.files = true;
+        }
+    }
+
+    fn close_dir(
+        &mut self,
+        dir: OpenDir<'a, Self::Dir>,
+        parent: Option<&mut OpenDir<'a, Self::Dir>>,
+    ) {
+        let mut dir = dir.contents;
+        let mut subdir_hashes = core::mem::take(&mut dir.subdir_hashes);
+        subdir_hashes.sort_unstable();
+        dir.hashes.sort_unstable();
+        dir.hashes.dedup();
+        // The directory is one of the deepest holding the hashes none of its subdirectories hold.
+        let own = dir.hashes.iter().filter(|h| subdir_hashes.binary_search(h).is_err());
+        own.for_each(|h| self.holders[*h] += 1);
+        dir.hashes.extend(subdir_hashes);
+        dir.hashes.sort_unstable();
+        dir.hashes.dedup();
+        let IndexedDir { hashes, total, .. } = &mut dir;
+        hashes.iter().for_each(|h| total.add(self.sizes[*h]));
+        if let Some(parent) = parent {
+            parent.contents.subdir_hashes.extend(&dir.hashes);
+            parent.contents.subdirs += 1;
+        }
+        self.dirs.push(dir);
+    }
+}
+
+/// Maps each of the `hashes` numbered hashes to the directories holding it for which `include`
+/// holds, in increasing order.
+fn postings(
+    dirs: &[IndexedDir],
+    hashes: usize,
+    include: impl Fn(usize) -> bool,
+) -> Vec<Vec<usize>> {
+    let mut index = vec![Vec::new(); hashes];
+    let indexed = dirs.iter().enumerate().filter(|(d, _)| include(*d));
+    indexed.for_each(|(i, d)| d.hashes.iter().for_each(|h| index[*h].push(i)));
+    index
+}
+
+
+
+// ==================
+// === IndexedDir ===
+// ==================
+
+/// A directory in a [`DirIndex`].
+struct IndexedDir<'a> {
+    path: &'a Path,
+    /// The numbers of the distinct hashes of the files under the directory, sorted once the
+    /// directory is complete. Until then, only those of the files directly under it.
+    hashes: Vec<usize>,
+    /// The numbers of the hashes of the files under the subdirectories, while the directory is
+    /// open.
+    subdir_hashes: Vec<usize>,
+    total: Content,
+    /// Whether the directory directly holds any file.
+    files: bool,
+    subdirs: usize,
+    parent: Option<usize>,
+}
+
+// === Main `impl` ===
+
+impl<'a> IndexedDir<'a> {
+    fn new(path: &'a Path) -> Self {
+        Self {
+            path,
+            hashes: Vec::new(),
+            subdir_hashes: Vec::new(),
+            total: Content::default(),
+            files: false,
+            subdirs: 0,
+            parent: None,
+        }
+    }
+
+    /// Whether the directory only holds a single subdirectory.
+    fn is_wrapper(&self) -> bool {
+        !self.files && self.subdirs == 1
+    }
+
+    fn holds(&self, hash: usize) -> bool {
+        self.hashes.binary_search(&hash).is_ok()
+    }
+
+    /// Returns the contents found both in this directory and in `other`, given the sizes of the
+    /// files with each hash.
+    fn shared(&self, other: &Self, sizes: &[u64]) -> Content {
+        let (small, large) = match self.hashes.len() <= other.hashes.len() {
+            true => (self, other),
+            false => (other, self),
+        };
+        let mut shared = Content::default();
+        let hashes = small.hashes.iter().filter(|h| large.holds(**h));
+        hashes.for_each(|h| shared.add(sizes[*h]));
+        shared
+    }
+}
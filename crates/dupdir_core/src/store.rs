@@ -0,0 +1,584 @@
+use crate::prelude::*;
+
+use crate::Changes;
+use core::str;
+use std::fs;
+use std::io::Write as _;
+#[cfg(feature = "sqlite")]
+use std::path;
+
+
+
+// ==================
+// === StateStore ===
+// ==================
+
+/// A persistent backend for [`State`].
+pub trait StateStore {
+    /// Loads the full state, or an empty state if nothing has been stored yet.
+    fn load(&mut self) -> Result<State>;
+
+    /// Persists the state.
+    ///
+    /// Stores which support incremental updates only write the changes recorded since the state
+    /// was loaded or last saved. The recorded changes are cleared afterwards.
+    fn save(&mut self, state: &mut State) -> Result<()>;
+
+    /// Returns the paths of all stored files with the given content hash.
+    fn find_by_hash(&mut self, hash: &str) -> Result<Vec<PathBuf>>;
+
+    /// Returns all stored entries for `prefix` itself or any path beneath it.
+    fn find_by_prefix(&mut self, prefix: &Path) -> Result<Vec<(PathBuf, Entry)>>;
+}
+
+/// Opens the store for the state file at `path`, choosing the backend from its extension.
+pub fn open(path: &Path) -> Box<dyn StateStore> {
+    match StoreKind::from_path(path) {
+        StoreKind::Json => Box::new(JsonStore::new(path)),
+        #[cfg(feature = "sqlite")]
+        StoreKind::Sqlite => Box::new(SqliteStore::new(path)),
+    }
+}
+
+/// Copies the state stored at `from` into the store at `to`, returning the migrated state.
+///
+/// Typically used to move an existing `state.json` into a SQLite database in one shot.
+pub fn migrate(from: &Path, to: &Path) -> Result<State> {
+    let mut state = State::load(from)?;
+    state.mark_all_changed();
+    state.path = to.to_path_buf();
+    state.save()?;
+    Ok(state)
+}
+
+
+
+// =================
+// === StoreKind ===
+// =================
+
+/// The available [`StateStore`] backends.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[must_use]
+pub enum StoreKind {
+    /// The whole state as a single JSON document.
+    #[default]
+    Json,
+    /// An embedded SQLite database, updated incrementally.
+    #[cfg(feature = "sqlite")]
+    Sqlite,
+}
+
+// === Main `impl` ===
+
+impl StoreKind {
+    /// Picks the backend for a state file based on its extension, defaulting to JSON.
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path.extension();
+        let extension = extension.and_then(|e| e.to_str());
+        match extension {
+            #[cfg(feature = "sqlite")]
+            Some("sqlite" | "sqlite3" | "db") => Self::Sqlite,
+            _ => Self::Json,
+        }
+    }
+
+    /// The name of the state file for this backend in the default state directory.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            Self::Json => crate::STATE_JSON,
+            #[cfg(feature = "sqlite")]
+            Self::Sqlite => crate::STATE_SQLITE,
+        }
+    }
+}
+
+// === Trait `impl`s ===
+
+impl str::FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = match s {
+            "json" => Self::Json,
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Self::Sqlite,
+            _ => Err(format!("Unknown store: {s}"))?,
+        };
+        Ok(kind)
+    }
+}
+
+
+
+// =================
+// === JsonStore ===
+// =================
+
+/// Stores the whole state as one pretty-printed JSON document.
+///
+/// Every save rewrites the full document, so this is best suited to small and medium trees.
+#[derive(Clone, Debug)]
+#[must_use]
+pub struct JsonStore {
+    path: PathBuf,
+}
+
+// === Main `impl` ===
+
+impl JsonStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let path = path.to_path_buf();
+        Self { path }
+    }
+}
+
+// === Trait `impl`s ===
+
+impl StateStore for JsonStore {
+    fn load(&mut self) -> Result<State> {
+        let path = &self.path;
+        if !path.exists() {
+            return Ok(State::default());
+        }
+        let json = fs::read_to_string(path).map_err(Error::io(path))?;
+        let version = serde_json::from_str::<Version>(&json).map_err(Error::json(path))?;
+        match version.version {
+            SCHEMA_VERSION => serde_json::from_str(&json).map_err(Error::json(path)),
+            version if version < SCHEMA_VERSION => {
+                eprintln!("Migrating state from version {version} to {SCHEMA_VERSION}...");
+                let value = serde_json::from_str(&json).map_err(Error::json(path))?;
+                let value = migrate_json(path, value, version)?;
+                let state = serde_json::from_value::<State>(value);
+                let mut state = state.map_err(Error::json(path))?;
+                state.mark_all_changed();
+                Ok(state)
+            }
+            version => Err(unsupported_version(path, version)),
+        }
+    }
+
+    fn save(&mut self, state: &mut State) -> Result<()> {
+        let version = SCHEMA_VERSION;
+        let json = Versioned { version, state };
+        let path = &self.path;
+        let json = serde_json::to_string_pretty(&json).map_err(Error::json(path))?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(Error::io(parent))?;
+        }
+        // Write to a temporary file and rename it over the state file, so that a crash mid-write
+        // never leaves a truncated state behind.
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut file = fs::File::create(&tmp_path).map_err(Error::io(&tmp_path))?;
+        file.write_all(json.as_bytes()).map_err(Error::io(&tmp_path))?;
+        file.sync_all().map_err(Error::io(&tmp_path))?;
+        fs::rename(&tmp_path, path).map_err(Error::io(path))?;
+        state.changes = Changes::default();
+        Ok(())
+    }
+
+    fn find_by_hash(&mut self, hash: &str) -> Result<Vec<PathBuf>> {
+        let state = self.load()?;
+        let entries = state.hashes.into_iter();
+        let entries = entries.filter(|(_, e)| e.hash == hash);
+        Ok(entries.map(|(p, _)| p).collect())
+    }
+
+    fn find_by_prefix(&mut self, prefix: &Path) -> Result<Vec<(PathBuf, Entry)>> {
+        let state = self.load()?;
+        let entries = state.hashes.into_iter();
+        let entries = entries.filter(|(p, _)| p.starts_with(prefix));
+        Ok(entries.collect())
+    }
+}
+
+
+
+// ==================
+// === Migrations ===
+// ==================
+
+/// Version of the stored state format.
+///
+/// - `0`: unversioned; entries were bare hashes (before metadata was tracked) or hashes with
+///   metadata, always computed with `t1ha2`.
+/// - `1`: adds the `version` and the hash `algorithm`.
+/// - `2`: stores paths losslessly, so that they need not be valid UTF-8. JSON paths are encoded
+///   with [`encode_path`](crate::paths::encode_path), and SQLite paths are stored as blobs of
+///   raw bytes.
+/// - `3`: adds the [`Stage`](crate::Stage) each entry was hashed to. Earlier entries were hashed
+///   in full, which is the default stage.
+pub const SCHEMA_VERSION: u32 = 3;
+/// The algorithm used by all states written before the algorithm was recorded.
+const LEGACY_ALGORITHM: &str = "t1ha2";
+
+/// Reads only the schema version of a JSON state, which is `0` if absent.
+#[derive(Deserialize)]
+struct Version {
+    #[serde(default)]
+    version: u32,
+}
+
+#[derive(Serialize)]
+struct Versioned<'a> {
+    version: u32,
+    #[serde(flatten)]
+    state: &'a State,
+}
+
+/// Upgrades a JSON state from `version` to [`SCHEMA_VERSION`], one version at a time.
+fn migrate_json(
+    path: &Path,
+    mut value: serde_json::Value,
+    version: u32,
+) -> Result<serde_json::Value> {
+    use serde_json::Value;
+
+    if version < 1 {
+        let path = path.to_path_buf();
+        let reason = "State should be an object";
+        let state = value.as_object_mut().ok_or(Error::InvalidState { path, reason })?;
+        // Entries used to be bare hashes. An empty metadata never matches a real file, so these
+        // are rehashed on the next scan.
+        let hashes = state.get_mut("hashes").and_then(Value::as_object_mut);
+        hashes.into_iter().flat_map(|h| h.values_mut()).for_each(|entry| {
+            if let Value::String(hash) = entry {
+                let metadata = serde_json::json!(Metadata::default());
+                let hash = Value::String(hash.clone());
+                *entry = serde_json::json!({ "hash": hash, "metadata": metadata });
+            }
+        });
+        // The flat file list was replaced by per-root bookkeeping.
+        state.remove("files");
+        let algorithm = Value::String(LEGACY_ALGORITHM.to_string());
+        state.insert("algorithm".to_string(), algorithm);
+    }
+    if version < 2 {
+        let path = path.to_path_buf();
+        let reason = "State should be an object";
+        let state = value.as_object_mut().ok_or(Error::InvalidState { path, reason })?;
+        // Paths were plain strings, so the escape character itself needs escaping.
+        let escape = |p: &str| p.replace('%', "%25");
+        let escape_keys = |map: &mut serde_json::Map<String, Value>| {
+            *map = std::mem::take(map).into_iter().map(|(k, v)| (escape(&k), v)).collect();
+        };
+        let hashes = state.get_mut("hashes").and_then(Value::as_object_mut);
+        hashes.into_iter().for_each(escape_keys);
+        let roots = state.get_mut("roots").and_then(Value::as_object_mut);
+        roots.into_iter().for_each(|roots| {
+            escape_keys(roots);
+            let files = roots.values_mut().filter_map(|r| r.get_mut("files"));
+            let files = files.filter_map(Value::as_array_mut).flatten();
+            files.for_each(|f| {
+                if let Value::String(p) = f {
+                    *p = escape(p);
+                }
+            });
+        });
+    }
+    Ok(value)
+}
+
+fn unsupported_version(path: &Path, version: u32) -> Error {
+    let path = path.to_path_buf();
+    let supported = SCHEMA_VERSION;
+    Error::UnsupportedVersion { path, version, supported }
+}
+
+
+
+// ===================
+// === SqliteStore ===
+// ===================
+
+/// Stores the state in an embedded SQLite database.
+///
+/// Saves only write the entries and roots which changed, and lookups by hash or by path prefix
+/// are served from indexes without loading the whole state.
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+#[must_use]
+pub struct SqliteStore {
+    path: PathBuf,
+    connection: Option<rusqlite::Connection>,
+}
+
+/// The tables as of version `2`. Later columns are added by [`migrate_sqlite`], on new databases
+/// too.
+#[cfg(feature = "sqlite")]
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS entries (
+        path      BLOB    NOT NULL PRIMARY KEY,
+        hash      TEXT    NOT NULL,
+        size      INTEGER NOT NULL,
+        mtime_ns  INTEGER NOT NULL,
+        inode     INTEGER NOT NULL,
+        device    INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS entries_by_hash ON entries (hash);
+    CREATE TABLE IF NOT EXISTS roots (
+        path       BLOB    NOT NULL PRIMARY KEY,
+        dropped    INTEGER NOT NULL,
+        kept       INTEGER NOT NULL,
+        refreshed  INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS root_files (
+        root  BLOB NOT NULL,
+        path  BLOB NOT NULL,
+        PRIMARY KEY (root, path)
+    );
+    CREATE TABLE IF NOT EXISTS meta (
+        key    TEXT NOT NULL PRIMARY KEY,
+        value  TEXT NOT NULL
+    );
+";
+
+// === Main `impl` ===
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let path = path.to_path_buf();
+        Self { path, connection: None }
+    }
+
+    fn connection(&mut self) -> Result<&mut rusqlite::Connection> {
+        let connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => open_sqlite(&self.path)?,
+        };
+        Ok(self.connection.insert(connection))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(path: &Path) -> Result<rusqlite::Connection> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(Error::io(parent))?;
+    }
+    let connection = rusqlite::Connection::open(path).map_err(Error::sqlite(path))?;
+    connection.execute_batch(SQLITE_SCHEMA).map_err(Error::sqlite(path))?;
+    migrate_sqlite(path, &connection)?;
+    Ok(connection)
+}
+
+/// Upgrades the database to [`SCHEMA_VERSION`], tracked in SQLite's `user_version`.
+#[cfg(feature = "sqlite")]
+fn migrate_sqlite(path: &Path, connection: &rusqlite::Connection) -> Result<()> {
+    let sql = "PRAGMA user_version";
+    let version = connection.query_row(sql, [], |row| row.get::<_, u32>(0));
+    let version = version.map_err(Error::sqlite(path))?;
+    if version > SCHEMA_VERSION {
+        return Err(unsupported_version(path, version));
+    }
+    if version < 1 {
+        let sql = "SELECT EXISTS (SELECT 1 FROM entries)";
+        let has_entries = connection.query_row(sql, [], |row| row.get::<_, bool>(0));
+        if has_entries.map_err(Error::sqlite(path))? {
+            let sql = "INSERT OR IGNORE INTO meta VALUES ('algorithm', ?1)";
+            connection.execute(sql, [LEGACY_ALGORITHM]).map_err(Error::sqlite(path))?;
+        }
+    }
+    if version < 2 {
+        // Paths were stored as UTF-8 text. Text and blobs never compare equal in SQLite, so every
+        // path is converted to match the blobs written from now on.
+        let sql = "
+            UPDATE entries SET path = CAST(path AS BLOB);
+            UPDATE roots SET path = CAST(path AS BLOB);
+            UPDATE root_files SET root = CAST(root AS BLOB), path = CAST(path AS BLOB);
+        ";
+        connection.execute_batch(sql).map_err(Error::sqlite(path))?;
+    }
+    if version < 3 {
+        // Stages are stored by their index, and earlier entries were all hashed in full.
+        let sql = "ALTER TABLE entries ADD COLUMN stage INTEGER NOT NULL DEFAULT 2";
+        connection.execute_batch(sql).map_err(Error::sqlite(path))?;
+    }
+    let sql = format!("PRAGMA user_version = {SCHEMA_VERSION}");
+    connection.execute_batch(&sql).map_err(Error::sqlite(path))
+}
+
+// === Trait `impl`s ===
+
+#[cfg(feature = "sqlite")]
+impl StateStore for SqliteStore {
+    fn load(&mut self) -> Result<State> {
+        if !self.path.exists() {
+            return Ok(State::default());
+        }
+        let path = self.path.clone();
+        let connection = self.connection()?;
+        load_sqlite(connection).map_err(Error::sqlite(path))
+    }
+
+    fn save(&mut self, state: &mut State) -> Result<()> {
+        let path = self.path.clone();
+        let connection = self.connection()?;
+        save_sqlite(connection, state).map_err(Error::sqlite(path))?;
+        state.changes = Changes::default();
+        Ok(())
+    }
+
+    fn find_by_hash(&mut self, hash: &str) -> Result<Vec<PathBuf>> {
+        let path = self.path.clone();
+        let connection = self.connection()?;
+        let sql = "SELECT path FROM entries WHERE hash = ?1";
+        let paths = connection.prepare(sql).and_then(|mut statement| {
+            let paths = statement.query_map([hash], |row| row.get::<_, Vec<u8>>(0))?;
+            paths.map(|p| p.map(path_from_bytes)).collect()
+        });
+        paths.map_err(Error::sqlite(path))
+    }
+
+    fn find_by_prefix(&mut self, prefix: &Path) -> Result<Vec<(PathBuf, Entry)>> {
+        // Paths beneath `prefix` sort between `prefix/` and `prefix0` (`'0'` follows `'/'`), which
+        // lets the primary key index serve the lookup.
+        let mut prefix = path_to_bytes(prefix);
+        while let Some((&last, rest)) = prefix.split_last() {
+            match path::is_separator(last.into()) {
+                true => prefix = rest,
+                false => break,
+            }
+        }
+        let lower = [prefix, b"/"].concat();
+        let upper = [prefix, b"0"].concat();
+        let path = self.path.clone();
+        let connection = self.connection()?;
+        let sql = "SELECT * FROM entries WHERE path = ?1 OR (path >= ?2 AND path < ?3)";
+        let entries = connection.prepare(sql).and_then(|mut statement| {
+            let entries = statement.query_map([prefix, &lower, &upper], read_entry)?;
+            entries.collect()
+        });
+        entries.map_err(Error::sqlite(path))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+fn load_sqlite(connection: &rusqlite::Connection) -> rusqlite::Result<State> {
+    let mut state = State::default();
+
+    let sql = "SELECT value FROM meta WHERE key = 'algorithm'";
+    let algorithm = connection.query_row(sql, [], |row| row.get(0));
+    state.algorithm = rusqlite::OptionalExtension::optional(algorithm)?;
+
+    let mut statement = connection.prepare("SELECT * FROM roots")?;
+    let roots = statement.query_map([], |row| {
+        let path = row.get::<_, Vec<u8>>("path")?;
+        let dropped = row.get::<_, i64>("dropped")? as usize;
+        let kept = row.get::<_, i64>("kept")? as usize;
+        let refreshed = row.get::<_, i64>("refreshed")? as usize;
+        let last_scan = Stats { dropped, kept, refreshed };
+        let root = Root { files: BTreeSet::new(), last_scan };
+        Ok((path_from_bytes(path), root))
+    })?;
+    state.roots = roots.collect::<Result<_, _>>()?;
+
+    let mut statement = connection.prepare("SELECT * FROM root_files")?;
+    let files = statement.query_map([], |row| {
+        let root = row.get::<_, Vec<u8>>("root")?;
+        let path = row.get::<_, Vec<u8>>("path")?;
+        Ok((path_from_bytes(root), path_from_bytes(path)))
+    })?;
+    files.into_iter().try_for_each(|file| {
+        let (root, path) = file?;
+        let root = state.roots.entry(root).or_default();
+        root.files.insert(path);
+        Ok::<_, rusqlite::Error>(())
+    })?;
+
+    let mut statement = connection.prepare("SELECT * FROM entries")?;
+    let entries = statement.query_map([], read_entry)?;
+    state.hashes = entries.collect::<Result<_, _>>()?;
+    Ok(state)
+}
+
+/// Writes the changes recorded in `state` in a single transaction.
+#[cfg(feature = "sqlite")]
+fn save_sqlite(connection: &mut rusqlite::Connection, state: &State) -> rusqlite::Result<()> {
+    let transaction = connection.transaction()?;
+    {
+        let State { algorithm, roots, hashes, changes, .. } = state;
+
+        if changes.replaced {
+            let tables = ["entries", "roots", "root_files", "meta"];
+            let sql = tables.map(|table| format!("DELETE FROM {table};")).concat();
+            transaction.execute_batch(&sql)?;
+        }
+
+        if changes.algorithm {
+            let sql = "INSERT OR REPLACE INTO meta VALUES ('algorithm', ?1)";
+            let mut statement = transaction.prepare(sql)?;
+            if let Some(algorithm) = algorithm {
+                statement.execute([algorithm])?;
+            }
+        }
+
+        let sql = "DELETE FROM entries WHERE path = ?1";
+        let mut statement = transaction.prepare(sql)?;
+        changes.removed.iter().try_for_each(|p| statement.execute([path_to_bytes(p)]).map(drop))?;
+
+        let sql = "INSERT OR REPLACE INTO entries VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)";
+        let mut statement = transaction.prepare(sql)?;
+        let upserted = changes.upserted.iter();
+        let upserted = upserted.filter_map(|p| hashes.get_key_value(p));
+        upserted.into_iter().try_for_each(|(p, e)| {
+            let Metadata { size, mtime_ns, inode, device } = e.metadata;
+            // SQLite integers are signed, so the unsigned fields are stored bit-for-bit.
+            let (size, inode, device) = (size as i64, inode as i64, device as i64);
+            let stage = e.stage as i64;
+            let path = path_to_bytes(p);
+            let params = rusqlite::params![path, e.hash, size, mtime_ns, inode, device, stage];
+            statement.execute(params).map(drop)
+        })?;
+
+        let sql = "DELETE FROM root_files WHERE root = ?1 AND path = ?2";
+        let mut statement = transaction.prepare(sql)?;
+        let removed = changes.root_files_removed.iter();
+        removed.into_iter().try_for_each(|(r, p)| {
+            let params = [path_to_bytes(r), path_to_bytes(p)];
+            statement.execute(params).map(drop)
+        })?;
+
+        let sql = "INSERT OR IGNORE INTO root_files VALUES (?1, ?2)";
+        let mut statement = transaction.prepare(sql)?;
+        let added = changes.root_files_added.iter();
+        added.into_iter().try_for_each(|(r, p)| {
+            let params = [path_to_bytes(r), path_to_bytes(p)];
+            statement.execute(params).map(drop)
+        })?;
+
+        let sql = "INSERT OR REPLACE INTO roots VALUES (?1, ?2, ?3, ?4)";
+        let mut statement = transaction.prepare(sql)?;
+        let changed = changes.roots.iter();
+        let changed = changed.filter_map(|r| roots.get_key_value(r));
+        changed.into_iter().try_for_each(|(r, root)| {
+            let Stats { dropped, kept, refreshed } = root.last_scan;
+            let (dropped, kept, refreshed) = (dropped as i64, kept as i64, refreshed as i64);
+            let params = rusqlite::params![path_to_bytes(r), dropped, kept, refreshed];
+            statement.execute(params).map(drop)
+        })?;
+    }
+    transaction.commit()
+}
+
+#[cfg(feature = "sqlite")]
+fn read_entry(row: &rusqlite::Row<'_>) -> rusqlite::Result<(PathBuf, Entry)> {
+    let path = row.get::<_, Vec<u8>>("path")?;
+    let hash = row.get("hash")?;
+    let size = row.get::<_, i64>("size")? as u64;
+    let mtime_ns = row.get("mtime_ns")?;
+    let inode = row.get::<_, i64>("inode")? as u64;
+    let device = row.get::<_, i64>("device")? as u64;
+    let metadata = Metadata { size, mtime_ns, inode, device };
+    let stage = match row.get::<_, i64>("stage")? {
+        0 => Stage::Size,
+        1 => Stage::Sample,
+        _ => Stage::Full,
+    };
+    let entry = Entry { hash, metadata, stage };
+    Ok((path_from_bytes(path), entry))
+}
@@ -0,0 +1,237 @@
+use crate::prelude::*;
+
+#[cfg(feature = "rayon")]
+use indicatif::ParallelProgressIterator as _;
+use indicatif::ProgressIterator as _;
+#[cfg(feature = "rayon")]
+use rayon::iter::IntoParallelIterator as _;
+#[cfg(feature = "rayon")]
+use rayon::iter::ParallelIterator as _;
+use std::fs;
+
+
+
+// =================
+// === Constants ===
+// =================
+
+/// The cryptographic algorithm files sharing a hash are hashed again with to verify them.
+const VERIFY_ALGORITHM: Algorithm = Algorithm::Blake3;
+/// The cryptographic algorithm used instead of [`VERIFY_ALGORITHM`] when files were already
+/// hashed with it, as hashing them again with it could only confirm its collisions.
+const FALLBACK_VERIFY_ALGORITHM: Algorithm = Algorithm::Sha256;
+
+
+
+// ================
+// === Verified ===
+// ================
+
+/// The outcome of [`verify_files`] and [`verify_dirs`].
+///
+/// Files and directories which turned out to differ from the first ones with their hash are given
+/// a hash of their own, made of the shared hash followed by `-` and a number.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct Verified {
+    /// The hashes given to the files which differ from the first file with their hash, by path.
+    hashes: HashMap<PathBuf, String>,
+    /// The number of groups of files or directories which shared a hash despite different
+    /// contents, and were split.
+    pub collisions: usize,
+    /// Files which could not be read, when collected under [`ErrorPolicy::Collect`]. They are
+    /// given a hash of their own, as they can't be confirmed to be duplicates.
+    pub errors: Vec<Error>,
+}
+
+// === Main `impl` ===
+
+impl Verified {
+    /// Returns the hash of the file at `path`, which only files with the same contents share. A
+    /// default `Verified` returns the hash of the entry as it is.
+    pub fn hash<'a>(&'a self, path: &Path, entry: &'a Entry) -> &'a str {
+        self.hashes.get(path).unwrap_or(&entry.hash)
+    }
+
+    /// Gives each class of items beyond the first a hash of its own, made from `hash`, and counts
+    /// a collision if there is more than one class.
+    fn split<T>(&mut self, hash: &str, classes: Vec<Vec<T>>, mut rehash: impl FnMut(T, String)) {
+        if classes.len() > 1 {
+            self.collisions += 1;
+        }
+        let classes = classes.into_iter().enumerate().skip(1);
+        classes.for_each(|(i, class)| {
+            class.into_iter().for_each(|t| rehash(t, format!("{hash}-{i}")));
+        });
+    }
+}
+
+
+
+// ====================
+// === verify_files ===
+// ====================
+
+/// Hashes the files under `root` which share their hash with another again, with a cryptographic
+/// algorithm other than [`Options::algorithm`], and tells apart those which turn out to differ.
+/// Does nothing unless [`Options::verify`] is set.
+///
+/// Empty files and [`Options::junk_files`] are left out, as in [`dup_files`](crate::dup_files).
+pub fn verify_files(state: &State, root: &Path, options: &Options) -> Result<Verified> {
+    let mut verified = Verified::default();
+    if !options.verify {
+        return Ok(verified);
+    }
+    eprintln!("Verifying duplicate files...");
+    let interrupt = &options.interrupt;
+    let algorithm = match options.algorithm {
+        VERIFY_ALGORITHM => FALLBACK_VERIFY_ALGORITHM,
+        _ => VERIFY_ALGORITHM,
+    };
+    let entries = state.root_entries(root);
+    let entries = entries.filter(|(p, e)| options.is_compared(p, e));
+    let mut groups = HashMap::<&str, Vec<&PathBuf>>::new();
+    entries.for_each(|(p, e)| groups.entry(&e.hash).or_default().push(p));
+    groups.retain(|_, files| files.len() > 1);
+
+    let files = groups.values().flatten().copied().collect::<Vec<_>>();
+    let len = files.len() as u64;
+    #[cfg(feature = "rayon")]
+    let files = files.into_par_iter();
+    #[cfg(not(feature = "rayon"))]
+    let files = files.into_iter();
+    let digests = files.progress_count(len).filter_map(|p| {
+        if crate::is_interrupted(interrupt) {
+            return None;
+        }
+        Some((p, verify_path(p, algorithm, options.symlinks)))
+    });
+    let mut digests = digests.collect::<HashMap<_, _>>();
+    if crate::is_interrupted(interrupt) {
+        return Err(Error::Interrupted);
+    }
+
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_unstable();
+    groups.into_iter().try_for_each(|(hash, mut files)| {
+        files.sort_unstable();
+        let mut classes = Vec::<(Vec<u8>, Vec<&PathBuf>)>::new();
+        let mut unread = Vec::new();
+        files.into_iter().try_for_each(|p| {
+            match digests.remove(p).expect("Digest of every verified file") {
+                Ok(digest) => match classes.iter_mut().find(|(d, _)| *d == digest) {
+                    Some((_, class)) => class.push(p),
+                    None => classes.push((digest, vec![p])),
+                },
+                Err(e) => {
+                    verified.errors.push(options.on_file_error.handle(e)?);
+                    unread.push(vec![p]);
+                },
+            }
+            Ok::<_, Error>(())
+        })?;
+        // Unreadable files come after the readable ones, so they never keep the shared hash.
+        let classes = classes.into_iter().map(|(_, class)| class).chain(unread);
+        let mut hashes = HashMap::new();
+        verified.split(hash, classes.collect(), |p, h| {
+            hashes.insert(p.clone(), h);
+        });
+        verified.hashes.extend(hashes);
+        Ok::<_, Error>(())
+    })?;
+    Ok(verified)
+}
+
+/// Hashes the contents of the file at `path` with `algorithm`, or its target if it is a symlink
+/// recorded under [`SymlinkPolicy::Record`].
+fn verify_path(path: &Path, algorithm: Algorithm, symlinks: SymlinkPolicy) -> Result<Vec<u8>> {
+    let (_, is_symlink) = crate::read_metadata(path, symlinks)?;
+    let mut hasher = algorithm.hasher();
+    if is_symlink {
+        let target = fs::read_link(path).map_err(Error::io(path))?;
+        hasher.update(crate::SYMLINK_HASH_PREFIX);
+        hasher.update(path_to_bytes(&target));
+    } else {
+        let mut file = fs::File::open(path).map_err(Error::io(path))?;
+        dupdir_hash::copy_wide_dyn(&mut file, hasher.as_mut()).map_err(Error::io(path))?;
+    }
+    Ok(hasher.finish())
+}
+
+
+
+// ===================
+// === verify_dirs ===
+// ===================
+
+/// Splits the groups of directories sharing a hash whose contents turn out to differ, according
+/// to the file hashes of `verified`, giving each part beyond the first a hash of its own. Does
+/// nothing unless [`Options::verify`] is set.
+///
+/// Directories are compared by the contents their hash stands for under
+/// [`Options::dir_hash_mode`] rather than by their hashes, so that collisions between the hashes
+/// of directories are caught as well as those between the hashes of files.
+pub fn verify_dirs(
+    state: &State,
+    root: &Path,
+    dir_hashes: &mut [(String, &Path)],
+    verified: &mut Verified,
+    options: &Options,
+) {
+    if !options.verify {
+        return;
+    }
+    eprintln!("Verifying duplicate directories...");
+    let mut groups = HashMap::<String, Vec<usize>>::new();
+    let dirs = dir_hashes.iter().enumerate();
+    dirs.for_each(|(i, (h, _))| groups.entry(h.clone()).or_default().push(i));
+    groups.retain(|_, dirs| dirs.len() > 1);
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    groups.sort_unstable();
+
+    let mut hashes = Vec::new();
+    groups.into_iter().progress().for_each(|(hash, mut dirs)| {
+        dirs.sort_unstable_by_key(|i| dir_hashes[*i].1);
+        let mut classes = Vec::<(Vec<(&Path, &str)>, Vec<usize>)>::new();
+        dirs.into_iter().for_each(|i| {
+            let contents = dir_contents(state, root, dir_hashes[i].1, verified, options);
+            match classes.iter_mut().find(|(c, _)| *c == contents) {
+                Some((_, class)) => class.push(i),
+                None => classes.push((contents, vec![i])),
+            }
+        });
+        let classes = classes.into_iter().map(|(_, class)| class).collect::<Vec<_>>();
+        hashes.push((hash, classes));
+    });
+    hashes.into_iter().for_each(|(hash, classes)| {
+        verified.split(&hash, classes, |i, h| dir_hashes[i].0 = h);
+    });
+}
+
+/// Returns the contents of `dir` which its hash stands for under [`Options::dir_hash_mode`], as
+/// the sorted paths relative to `dir` and hashes of its files. Paths are left empty unless the
+/// mode depends on them.
+fn dir_contents<'a>(
+    state: &'a State,
+    root: &Path,
+    dir: &'a Path,
+    verified: &'a Verified,
+    options: &Options,
+) -> Vec<(&'a Path, &'a str)> {
+    let mode = options.dir_hash_mode;
+    let files = state.dir_entries(root, dir);
+    let files = files.filter(|(p, _)| !options.is_junk_file(p));
+    let files = files.map(|(p, e)| {
+        let path = match mode {
+            DirHashMode::Multiset | DirHashMode::Set => Path::new(""),
+            DirHashMode::RelativePaths | DirHashMode::Merkle => p.strip_prefix(dir).unwrap_or(p),
+        };
+        (path, verified.hash(p, e))
+    });
+    let mut files = files.collect::<Vec<_>>();
+    files.sort_unstable();
+    if mode == DirHashMode::Set {
+        files.dedup();
+    }
+    files
+}
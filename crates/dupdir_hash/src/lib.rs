@@ -1,3 +1,5 @@
+use core::fmt;
+use core::str;
 use std::hash::Hasher as _;
 use std::io;
 
@@ -20,6 +22,11 @@ mod tests;
 // ==============
 
 pub trait Hasher<const DIGEST_SIZE: usize> {
+    /// Identifier of the algorithm, e.g. `"t1ha2"`.
+    ///
+    /// Stored alongside digests so that digests from different algorithms are never compared.
+    const NAME: &'static str;
+
     fn update(&mut self, data: &[u8]);
 
     fn finish(self) -> [u8; DIGEST_SIZE];
@@ -29,13 +36,17 @@ pub fn copy_wide<const DIGEST_SIZE: usize>(
     reader: &mut impl io::Read,
     hasher: &mut impl Hasher<DIGEST_SIZE>,
 ) -> io::Result<u64> {
+    copy_wide_with(reader, |data| hasher.update(data))
+}
+
+fn copy_wide_with(reader: &mut impl io::Read, mut update: impl FnMut(&[u8])) -> io::Result<u64> {
     let mut buffer = [0u8; BUF_SIZE];
     let mut total = 0;
     loop {
         match reader.read(&mut buffer) {
             Ok(0) => return Ok(total),
             Ok(n) => {
-                hasher.update(&buffer[..n]);
+                update(&buffer[..n]);
                 total += n as u64;
             }
             Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
@@ -44,6 +55,23 @@ pub fn copy_wide<const DIGEST_SIZE: usize>(
     }
 }
 
+// =================
+// === DynHasher ===
+// =================
+
+/// A [`Hasher`] whose digest size is only known at runtime, so that the algorithm can be chosen
+/// at runtime through [`Algorithm::hasher`].
+pub trait DynHasher {
+    fn update(&mut self, data: &[u8]);
+
+    fn finish(self: Box<Self>) -> Vec<u8>;
+}
+
+/// Like [`copy_wide`], for a hasher chosen at runtime.
+pub fn copy_wide_dyn(reader: &mut impl io::Read, hasher: &mut dyn DynHasher) -> io::Result<u64> {
+    copy_wide_with(reader, |data| hasher.update(data))
+}
+
 // =====================
 // === define_hasher ===
 // =====================
@@ -192,11 +220,26 @@ macro_rules! impl_hash {
 
             #[cfg(feature = "hash-" $ident)]
             impl $( <$( $gen ),*> )? Hasher<$size> for $ident $( <$( $gen ),*> )? {
+                const NAME: &'static str = stringify!([<$ident:snake:lower>]);
+
                 define_hasher!(
                     $size,
                     $( $tail )*
                 );
             }
+
+            #[cfg(feature = "hash-" $ident)]
+            impl $( <$( $gen ),*> )? DynHasher for $ident $( <$( $gen ),*> )? {
+                #[inline]
+                fn update(&mut self, data: &[u8]) {
+                    Hasher::update(self, data);
+                }
+
+                #[inline]
+                fn finish(self: Box<Self>) -> Vec<u8> {
+                    Hasher::finish(*self).to_vec()
+                }
+            }
         }
     };
     (
@@ -378,3 +421,126 @@ impl_hash!(Xxh64, 8, xxhash_rust::xxh64::Xxh64, std_hasher_per_byte,);
 impl_hash!(Xxh64TwoHash, 8, twox_hash::XxHash64, std_hasher_per_byte,);
 impl_hash!(T1ha2, 8, t1ha::T1ha2Hasher, std_hasher,);
 impl_hash!(Adler32Rolling, 4, adler32::RollingAdler32, rolling_adler32,);
+
+// =======================
+// === impl_algorithms ===
+// =======================
+
+macro_rules! impl_algorithms {
+    ($( $ident:ident ),*,) => {
+        paste::paste! {
+            // =================
+            // === Algorithm ===
+            // =================
+
+            /// One of the [`Hasher`]s, chosen at runtime, e.g. from its [`Hasher::NAME`].
+            #[derive(Clone, Copy, Debug, Eq, PartialEq)]
+            #[must_use]
+            pub enum Algorithm {
+            $(
+                #[cfg(feature = "hash-" $ident)]
+                $ident,
+            )*
+            }
+
+
+            // === Main `impl` ===
+
+            impl Algorithm {
+                /// The [`Hasher::NAME`] of the algorithm.
+                pub fn name(self) -> &'static str {
+                    match self {
+                    $(
+                        #[cfg(feature = "hash-" $ident)]
+                        Self::$ident => $ident::NAME,
+                    )*
+                    }
+                }
+
+                pub fn hasher(self) -> Box<dyn DynHasher> {
+                    match self {
+                    $(
+                        #[cfg(feature = "hash-" $ident)]
+                        Self::$ident => Box::<$ident>::default(),
+                    )*
+                    }
+                }
+
+                pub fn hash(self, reader: &mut impl io::Read) -> io::Result<Vec<u8>> {
+                    let mut hasher = self.hasher();
+                    copy_wide_dyn(reader, hasher.as_mut())?;
+                    Ok(hasher.finish())
+                }
+            }
+
+
+            // === Trait `impl`s ===
+
+            impl str::FromStr for Algorithm {
+                type Err = String;
+
+                fn from_str(s: &str) -> Result<Self, Self::Err> {
+                    match s {
+                    $(
+                        #[cfg(feature = "hash-" $ident)]
+                        stringify!([<$ident:snake:lower>]) => Ok(Self::$ident),
+                    )*
+                        _ => Err(format!("Unknown algorithm: \"{s}\".")),
+                    }
+                }
+            }
+
+            impl fmt::Display for Algorithm {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    f.write_str(self.name())
+                }
+            }
+        }
+    }
+}
+
+impl_algorithms!(
+    Adler32,
+    Adler32Rolling,
+    BeltHash,
+    Blake2b,
+    Blake2bSimd,
+    Blake2s,
+    Blake2sSimd,
+    Blake3,
+    Crc32Fast,
+    FarmHash,
+    Fnv,
+    Fsb256,
+    Fsb512,
+    FxHasher,
+    FxHasher32,
+    FxHasher64,
+    FxHasherRustc,
+    Groestl256,
+    Groestl512,
+    KangarooTwelve256,
+    KangarooTwelve512,
+    Md5,
+    MetroHash64,
+    MetroHash128,
+    Ripemd160,
+    Seahash,
+    Sha256,
+    Sha512,
+    Sha3_256,
+    Sha3_512,
+    Shabal512,
+    Siphash,
+    Sm3,
+    T1ha,
+    T1ha2,
+    Tiger,
+    Tiger2,
+    Whirlpool,
+    Xxh3,
+    Xxh64,
+    Xxh64TwoHash,
+    Xxh2_32,
+    Xxh2_64,
+);
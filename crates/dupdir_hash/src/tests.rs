@@ -54,6 +54,15 @@ macro_rules! impl_test {
                     let hash = hasher.finish();
                     assert_eq!(hex::encode(hash), $expected);
                 }
+
+                #[test]
+                fn hash_dyn() {
+                    let mut bytes = &b"Hello, world!"[..];
+                    let algorithm = crate::$ty::NAME.parse::<crate::Algorithm>().unwrap();
+                    assert_eq!(algorithm, crate::Algorithm::$ty);
+                    let hash = algorithm.hash(&mut bytes).unwrap();
+                    assert_eq!(hex::encode(hash), $expected);
+                }
             }
         }
     };
@@ -132,3 +141,15 @@ impl_test!(Xxh64, "f58336a78b6f9476");
 impl_test!(Xxh64TwoHash, "f58336a78b6f9476");
 impl_test!(Xxh2_32, "31b7405d");
 impl_test!(Xxh2_64, "f58336a78b6f9476");
+
+// =================
+// === Algorithm ===
+// =================
+
+#[cfg(feature = "hash-Sha256")]
+#[test]
+fn algorithm_from_str() {
+    assert_eq!("sha256".parse::<crate::Algorithm>(), Ok(crate::Algorithm::Sha256));
+    assert_eq!(crate::Algorithm::Sha256.to_string().parse(), Ok(crate::Algorithm::Sha256));
+    assert!("sha-256".parse::<crate::Algorithm>().is_err());
+}
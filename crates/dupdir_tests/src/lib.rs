@@ -17,10 +17,13 @@ mod tests;
 
 mod prelude {
     pub(crate) use dupdir_core::prelude::*;
-    pub(crate) use core::str;
-    pub(crate) use std::path::Path;
+    #[cfg(test)]
+    pub(crate) use std::fs;
+    pub(crate) use std::path::PathBuf;
     #[cfg(test)]
     pub(crate) use std::process::Command;
+    #[cfg(test)]
+    pub(crate) use tempdir::TempDir;
 }
 
 
@@ -40,7 +43,7 @@ struct UnixFinder {
 impl UnixFinder {
     #[cfg(test)]
     fn new(path: &str) -> Self {
-        let args = vec![path, "-type", "f"];
+        let args = vec![path, "-type", "f", "-print0"];
         let cmd = &mut Command::new("find");
         let cmd = cmd.args(args);
         let output = cmd.output();
@@ -56,18 +59,41 @@ impl UnixFinder {
 // === Trait `impl`s ===
 
 impl<'a> IntoIterator for &'a UnixFinder {
-    type Item = &'a Path;
-    type IntoIter = FinderIter<'a, &'a Path>;
+    type Item = Result<PathBuf>;
+    type IntoIter = FinderIter<'a, PathBuf>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let stdout = str::from_utf8(&self.stdout);
-        let stdout = stdout.expect("Failed to read stdout as utf8");
-        let lines = stdout.lines();
+        // Paths are NUL-terminated, since any other byte may appear in a path.
+        let stdout = self.stdout.strip_suffix(b"\0").unwrap_or(&self.stdout);
+        let lines = stdout.split(|b| *b == b'\0');
         let paths = lines.map(|p| {
-            assert_path_rules(p);
-            Path::new(p)
+            let p = path_from_bytes(p.to_vec());
+            check_path_rules(&p)?;
+            Ok(p)
         });
         let paths = Box::new(paths);
         Self::IntoIter { paths }
     }
 }
+
+
+
+// ==================
+// === write_tree ===
+// ==================
+
+/// Writes the given files, as paths relative to the root and their contents, under a `root`
+/// directory in a new temporary directory. Returns the temporary directory, which the test's state
+/// is kept in, and the root.
+#[cfg(test)]
+fn write_tree(files: &[(&str, &str)]) -> (TempDir, PathBuf) {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(&root).unwrap();
+    files.iter().for_each(|(f, contents)| {
+        let f = root.join(f);
+        fs::create_dir_all(f.parent().unwrap()).unwrap();
+        fs::write(f, contents).unwrap();
+    });
+    (dir, root)
+}
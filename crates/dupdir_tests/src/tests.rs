@@ -16,7 +16,12 @@ const REAL_FIND_PATH: &str = "/Users/indiv0/Desktop/files";
 
 mod prelude {
     pub(crate) use dupdir_core::prelude::*;
+    pub(crate) use std::collections::BTreeMap;
+    pub(crate) use std::collections::BTreeSet;
+    pub(crate) use std::fs;
+    pub(crate) use std::path::Path;
     pub(crate) use std::time::Instant;
+    pub(crate) use tempdir::TempDir;
 }
 
 
@@ -35,15 +40,15 @@ fn test_unix_and_walk_dir_are_identical() {
 fn find_unix() -> Vec<String> {
     let unix = crate::UnixFinder::new(REAL_FIND_PATH);
     let unix = unix.into_iter();
-    let unix = unix.map(crate::path_to_string);
-    unix.collect()
+    let unix = unix.map(|p| crate::path_to_string(p?));
+    unix.collect::<Result<_>>().unwrap()
 }
 
 fn find_walk_dir() -> Vec<String> {
     let walk_dir = crate::WalkDirFinder::new(REAL_FIND_PATH);
     let walk_dir = walk_dir.into_iter();
-    let walk_dir = walk_dir.map(crate::path_to_string);
-    walk_dir.collect()
+    let walk_dir = walk_dir.map(|p| crate::path_to_string(p?));
+    walk_dir.collect::<Result<_>>().unwrap()
 }
 
 
@@ -55,11 +60,14 @@ fn find_walk_dir() -> Vec<String> {
 #[test]
 fn test_dir_hashes_walk_dir_are_identical() {
     let start = Instant::now();
-    let mut state = State::load("../../state.json");
-    let walk_dir = dupdir_core::dir_hashes(&mut state);
+    let state = State::load("../../state.json").unwrap();
+    let options = baseline_options();
+    let walk_dir = dupdir_core::dir_hashes(&state, Path::new(REAL_FIND_PATH), &options);
     let end = Instant::now();
     let duration = end - start;
-    assert_eq!(walk_dir.len(), 33966);
+    // The directories above the root were hashed as well when the count was recorded.
+    let above_root = Path::new(REAL_FIND_PATH).ancestors().skip(1).count();
+    assert_eq!(walk_dir.len(), 33966 - above_root);
     println!("WalkDir: {:?}", duration);
 }
 
@@ -72,7 +80,1005 @@ fn test_dir_hashes_walk_dir_are_identical() {
 #[test]
 fn test_all() {
     // FIXME [NP]: const
-    let mut state = State::load("../../state.json");
-    let dupdirs = dupdir_core::run_all(&mut state, REAL_FIND_PATH);
+    let mut state = State::load("../../state.json").unwrap();
+    let search_path = Path::new(REAL_FIND_PATH);
+    let report = dupdir_core::run_all(&mut state, search_path, &baseline_options()).unwrap();
+    let dupdirs = report.dup_dirs;
     assert_eq!(dupdirs.len(), 26160);
 }
+
+/// Options under which directories are hashed as they were when the expected counts of the tests
+/// against [`REAL_FIND_PATH`] were recorded.
+fn baseline_options() -> Options {
+    let dir_hash_mode = DirHashMode::Set;
+    Options { junk_files: Vec::new(), dir_hash_mode, expanded: true, ..Default::default() }
+}
+
+
+
+// =============
+// === State ===
+// =============
+
+#[test]
+fn test_state_rehashes_only_modified_files() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::write(root.join("a/x"), "x").unwrap();
+    fs::write(root.join("a/y"), "y").unwrap();
+    let state_path = dir.path().join("state.json");
+    let search_path = &root;
+    // Files are hashed in full, so that hashes can be checked against the files' contents.
+    let options = Options { staged: false, ..Default::default() };
+
+    let mut state = State::load(&state_path).unwrap();
+    let _ = dupdir_core::run_all(&mut state, search_path, &options).unwrap();
+    let stats = last_scan(&state, &root);
+    assert_eq!(stats, Stats { dropped: 0, kept: 0, refreshed: 2 });
+
+    fs::write(root.join("a/y"), "modified").unwrap();
+    let mut state = State::load(&state_path).unwrap();
+    let _ = dupdir_core::run_all(&mut state, search_path, &options).unwrap();
+    let stats = last_scan(&state, &root);
+    assert_eq!(stats, Stats { dropped: 0, kept: 1, refreshed: 1 });
+    let hash = &state.entries()[&root.join("a/y")].hash;
+    assert_eq!(hash, &hash_of(&dir, "modified"));
+}
+
+#[test]
+fn test_state_gc_drops_deleted_and_out_of_scope_files() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::write(root.join("a/x"), "x").unwrap();
+    fs::write(root.join("a/y"), "y").unwrap();
+    let state_path = dir.path().join("state.json");
+
+    let mut state = State::load(state_path).unwrap();
+    let _ = run_all(&mut state, &root, &Options::default());
+    fs::remove_file(root.join("a/x")).unwrap();
+    let report = state.gc(&Options::default()).unwrap();
+    assert_eq!(report.stats, Stats { dropped: 1, kept: 1, refreshed: 0 });
+    assert_eq!(state.entries().len(), 1);
+
+    fs::remove_file(root.join("a/y")).unwrap();
+    let _ = run_all(&mut state, &root, &Options::default());
+    let stats = last_scan(&state, &root);
+    assert_eq!(stats, Stats { dropped: 1, kept: 0, refreshed: 0 });
+    assert!(state.entries().is_empty());
+}
+
+#[test]
+fn test_state_is_saved_to_its_load_path() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("x"), "x").unwrap();
+    let state_path = dir.path().join("profiles/photos/state.json");
+
+    let mut state = State::load(&state_path).unwrap();
+    let _ = run_all(&mut state, &root, &Options::default());
+    let state = State::load(state_path).unwrap();
+    assert_eq!(state.entries().len(), 1);
+    assert_eq!(state.roots().count(), 1);
+}
+
+#[test]
+fn test_state_migrates_legacy_json_and_checks_algorithm() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("x"), "x").unwrap();
+    let state_path = dir.path().join("state.json");
+    let legacy = format!(r#"{{"files":[],"hashes":{{{:?}:"0000"}}}}"#, root.join("x"));
+    fs::write(&state_path, legacy).unwrap();
+
+    // Legacy entries carry no metadata, so they are rehashed rather than trusted.
+    let mut state = State::load(&state_path).unwrap();
+    assert_eq!(state.algorithm(), Some("t1ha2"));
+    let _ = run_all(&mut state, &root, &Options::default());
+    assert_eq!(last_scan(&state, &root), Stats { dropped: 0, kept: 0, refreshed: 1 });
+
+    let json = fs::read_to_string(&state_path).unwrap();
+    fs::write(&state_path, json.replace("\"t1ha2\"", "\"blake3\"")).unwrap();
+    let mut state = State::load(&state_path).unwrap();
+    let result = dupdir_core::run_all(&mut state, &root, &Options::default());
+    assert!(matches!(result, Err(Error::AlgorithmMismatch { .. })));
+
+    let on_algorithm_mismatch = AlgorithmMismatch::Rehash;
+    let options = Options { on_algorithm_mismatch, ..Default::default() };
+    let _ = run_all(&mut state, &root, &options);
+    assert_eq!(state.algorithm(), Some(DEFAULT_ALGORITHM.name()));
+    assert_eq!(last_scan(&state, &root), Stats { dropped: 0, kept: 0, refreshed: 1 });
+}
+
+#[test]
+fn test_state_records_the_chosen_algorithm() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    ["a", "b"].iter().for_each(|d| {
+        fs::create_dir_all(root.join(d)).unwrap();
+        fs::write(root.join(d).join("x"), "x").unwrap();
+    });
+    let state_path = dir.path().join("state.json");
+    let mut state = State::load(&state_path).unwrap();
+    let options = Options { algorithm: Algorithm::Blake3, staged: false, ..Default::default() };
+    let report = run_all(&mut state, &root, &options);
+    let dup_dirs = report.dup_dirs.into_iter().map(|d| d.path).collect::<Vec<_>>();
+    assert_eq!(dup_dirs, vec![root.join("a"), root.join("b")]);
+
+    let state = State::load(&state_path).unwrap();
+    assert_eq!(state.algorithm(), Some("blake3"));
+    let expected = Algorithm::Blake3.hash(&mut &b"x"[..]).unwrap();
+    let expected = expected.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().concat();
+    assert_eq!(state.entries()[&root.join("a/x")].hash, expected);
+}
+
+#[test]
+fn test_file_errors_abort_or_are_collected() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("missing");
+
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let result = dupdir_core::run_all(&mut state, &root, &Options::default());
+    assert!(matches!(result, Err(Error::Walk(_))));
+
+    let on_file_error = ErrorPolicy::Collect;
+    let options = Options { on_file_error, ..Default::default() };
+    let report = run_all(&mut state, &root, &options);
+    assert_eq!(report.scan.errors.len(), 1);
+    assert!(report.scan.errors[0].is_per_file());
+    assert_eq!(report.scan.stats, Stats::default());
+    assert!(report.dup_dirs.is_empty());
+}
+
+fn run_all(state: &mut State, root: &Path, options: &Options) -> Report {
+    dupdir_core::run_all(state, root, options).unwrap()
+}
+
+fn last_scan(state: &State, root: &Path) -> Stats {
+    let mut roots = state.roots();
+    let (_, r) = roots.find(|(p, _)| *p == root).unwrap();
+    r.last_scan()
+}
+
+fn hash_of(dir: &TempDir, contents: &str) -> String {
+    let root = dir.path().join("hash_of");
+    fs::create_dir_all(&root).unwrap();
+    fs::write(root.join("file"), contents).unwrap();
+    let mut state = State::load(dir.path().join("hash_of.json")).unwrap();
+    let _ = run_all(&mut state, &root, &Options { staged: false, ..Default::default() });
+    let entry = &state.entries()[&root.join("file")];
+    entry.hash.clone()
+}
+
+
+
+// =============
+// === Store ===
+// =============
+
+#[test]
+fn test_sqlite_store_migrates_and_updates_incrementally() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::create_dir_all(root.join("ab")).unwrap();
+    fs::write(root.join("a/x"), "x").unwrap();
+    fs::write(root.join("ab/x"), "x").unwrap();
+    fs::write(root.join("ab/y"), "y").unwrap();
+    let json_path = dir.path().join("state.json");
+    let sqlite_path = dir.path().join("state.sqlite");
+
+    let mut state = State::load(&json_path).unwrap();
+    let _ = run_all(&mut state, &root, &Options::default());
+    let migrated = dupdir_core::store::migrate(&json_path, &sqlite_path).unwrap();
+    assert_eq!(migrated.entries().len(), 3);
+
+    // Rescanning an unchanged tree keeps every entry, and the incremental save preserves them.
+    fs::remove_file(root.join("ab/y")).unwrap();
+    let mut state = State::load(&sqlite_path).unwrap();
+    assert_eq!(state.entries().len(), 3);
+    let _ = run_all(&mut state, &root, &Options::default());
+    let state = State::load(&sqlite_path).unwrap();
+    assert_eq!(state.entries().len(), 2);
+    let (_, root_state) = state.roots().next().unwrap();
+    assert_eq!(root_state.files().len(), 2);
+    assert_eq!(root_state.last_scan(), Stats { dropped: 1, kept: 2, refreshed: 0 });
+
+    let mut store = dupdir_core::store::open(&sqlite_path);
+    let hash = &state.entries()[&root.join("a/x")].hash;
+    assert_eq!(store.find_by_hash(hash).unwrap().len(), 2);
+    let entries = store.find_by_prefix(&root.join("a")).unwrap();
+    let paths = entries.into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+    assert_eq!(paths, vec![root.join("a/x")]);
+}
+
+#[test]
+fn test_sqlite_migration_replaces_the_existing_database() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let old_root = dir.path().join("old");
+    let new_root = dir.path().join("new");
+    fs::create_dir_all(&old_root).unwrap();
+    fs::create_dir_all(&new_root).unwrap();
+    fs::write(old_root.join("x"), "x").unwrap();
+    fs::write(new_root.join("y"), "y").unwrap();
+    let json_path = dir.path().join("state.json");
+    let sqlite_path = dir.path().join("state.sqlite");
+
+    let mut state = State::load(&sqlite_path).unwrap();
+    let _ = run_all(&mut state, &old_root, &Options::default());
+    let mut state = State::load(&json_path).unwrap();
+    let _ = run_all(&mut state, &new_root, &Options::default());
+    let _ = dupdir_core::store::migrate(&json_path, &sqlite_path).unwrap();
+
+    let state = State::load(&sqlite_path).unwrap();
+    let paths = state.entries().keys().cloned().collect::<Vec<_>>();
+    assert_eq!(paths, vec![new_root.join("y")]);
+    let roots = state.roots().map(|(r, _)| r).collect::<Vec<_>>();
+    assert_eq!(roots, vec![new_root.as_path()]);
+}
+
+
+
+// =============
+// === Paths ===
+// =============
+
+#[cfg(unix)]
+#[test]
+fn test_non_utf8_paths_round_trip_through_both_stores() {
+    use std::os::unix::ffi::OsStrExt as _;
+
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    let latin1 = root.join(std::ffi::OsStr::from_bytes(b"caf\xe9"));
+    let percent = root.join("100%");
+    fs::create_dir_all(&latin1).unwrap();
+    fs::create_dir_all(&percent).unwrap();
+    fs::write(latin1.join("x"), "x").unwrap();
+    fs::write(percent.join("x"), "x").unwrap();
+    fs::write(root.join("y"), "y").unwrap();
+
+    for state_path in [dir.path().join("state.json"), dir.path().join("state.sqlite")] {
+        let mut state = State::load(&state_path).unwrap();
+        let report = run_all(&mut state, &root, &Options::default());
+        let dup_dirs = report.dup_dirs.iter().map(|d| escape_path(&d.path));
+        let dup_dirs = dup_dirs.collect::<Vec<_>>();
+        assert_eq!(dup_dirs, vec![escape_path(&percent), escape_path(&latin1)]);
+        assert!(dup_dirs[1].ends_with("/caf\\xe9"));
+
+        let state = State::load(&state_path).unwrap();
+        assert!(state.entries().contains_key(&latin1.join("x")));
+        assert!(state.entries().contains_key(&percent.join("x")));
+        let mut store = dupdir_core::store::open(&state_path);
+        let entries = store.find_by_prefix(&latin1).unwrap();
+        let paths = entries.into_iter().map(|(p, _)| p).collect::<Vec<_>>();
+        assert_eq!(paths, vec![latin1.join("x")]);
+    }
+
+    // Paths written before the lossless encoding are plain strings, including any `%`.
+    let state_path = dir.path().join("v1.json");
+    let metadata = r#"{"size":0,"mtime_ns":0,"inode":0,"device":0}"#;
+    let entry = format!(r#"{:?}:{{"hash":"0","metadata":{metadata}}}"#, percent);
+    fs::write(&state_path, format!(r#"{{"version":1,"hashes":{{{entry}}}}}"#)).unwrap();
+    let state = State::load(&state_path).unwrap();
+    assert_eq!(state.entries().keys().collect::<Vec<_>>(), vec![&percent]);
+}
+
+#[test]
+fn test_special_characters_in_paths_are_scanned_and_escaped() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    let names = ["a;b", " lead", "trail ", "cr\r", "new\nline", "it's"];
+    names.iter().for_each(|n| {
+        fs::create_dir_all(root.join(n)).unwrap();
+        fs::write(root.join(n).join("x"), "x").unwrap();
+    });
+    fs::write(root.join("y"), "y").unwrap();
+
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let report = run_all(&mut state, &root, &Options::default());
+    let paths = report.dup_dirs.iter().map(|d| d.path.clone()).collect::<BTreeSet<_>>();
+    assert_eq!(paths, names.iter().map(|n| root.join(n)).collect());
+
+    let record = |format: OutputFormat, name: &str| {
+        let mut out = Vec::new();
+        format.write_record(&mut out, "0f", &root.join(name)).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    let r = path_to_str(&root).unwrap();
+    assert_eq!(record(OutputFormat::Text, "a;b"), format!("0f;{r}/a;b\n"));
+    assert_eq!(record(OutputFormat::Text, "new\nline"), format!("0f;{r}/new\\nline\n"));
+    assert_eq!(record(OutputFormat::Quoted, "trail "), format!("0f;'{r}/trail '\n"));
+    assert_eq!(record(OutputFormat::Quoted, "it's"), format!("0f;'{r}/it'\\''s'\n"));
+    assert_eq!(record(OutputFormat::Quoted, "cr\r"), format!("0f;$'{r}/cr\\r'\n"));
+    assert_eq!(record(OutputFormat::Nul, "new\nline"), format!("0f;{r}/new\nline\0"));
+}
+
+
+
+// ================
+// === Symlinks ===
+// ================
+
+#[cfg(unix)]
+#[test]
+fn test_symlinks_are_skipped_followed_or_recorded() {
+    use std::os::unix::fs::symlink;
+    use std::os::unix::net::UnixListener;
+
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(root.join("a")).unwrap();
+    fs::create_dir_all(root.join("b")).unwrap();
+    fs::create_dir_all(root.join("c")).unwrap();
+    fs::write(root.join("a/file"), "contents").unwrap();
+    symlink("../a/file", root.join("b/link")).unwrap();
+    symlink("../a/file", root.join("c/link")).unwrap();
+    symlink(".", root.join("loop")).unwrap();
+    let _socket = UnixListener::bind(root.join("socket")).unwrap();
+    let entries = |state: &State| state.entries().keys().cloned().collect::<Vec<_>>();
+
+    let mut state = State::load(dir.path().join("skip.json")).unwrap();
+    let report = run_all(&mut state, &root, &Options::default());
+    assert_eq!(entries(&state), vec![root.join("a/file")]);
+    assert_eq!(report.scan.skipped.symlinks, 3);
+    assert_eq!(report.scan.skipped.sockets, 1);
+    assert!(report.scan.errors.is_empty());
+
+    // Loops are only detected when following symlinks, and are per-file errors.
+    let symlinks = SymlinkPolicy::Follow;
+    let on_file_error = ErrorPolicy::Collect;
+    let options = Options { symlinks, on_file_error, ..Default::default() };
+    let mut state = State::load(dir.path().join("follow.json")).unwrap();
+    let report = run_all(&mut state, &root, &options);
+    let expected = vec![root.join("a/file"), root.join("b/link"), root.join("c/link")];
+    assert_eq!(entries(&state), expected);
+    assert_eq!(report.scan.skipped, Skipped { sockets: 1, ..Default::default() });
+    assert_eq!(report.scan.errors.len(), 1);
+    let hashes = state.entries().values().map(|e| &e.hash).collect::<BTreeSet<_>>();
+    assert_eq!(hashes.len(), 1);
+
+    // Recorded symlinks are duplicates if their targets are, but never match a regular file.
+    let symlinks = SymlinkPolicy::Record;
+    let options = Options { symlinks, ..Default::default() };
+    let mut state = State::load(dir.path().join("record.json")).unwrap();
+    let report = run_all(&mut state, &root, &options);
+    let expected = vec![
+        root.join("a/file"),
+        root.join("b/link"),
+        root.join("c/link"),
+        root.join("loop"),
+    ];
+    assert_eq!(entries(&state), expected);
+    assert_eq!(report.scan.skipped, Skipped { sockets: 1, ..Default::default() });
+    let hash = |p: &str| state.entries()[&root.join(p)].hash.clone();
+    assert_eq!(hash("b/link"), hash("c/link"));
+    assert_ne!(hash("b/link"), hash_of(&dir, "../a/file"));
+    let dup_dirs = report.dup_dirs.iter().map(|d| d.path.clone()).collect::<Vec<_>>();
+    assert_eq!(dup_dirs, vec![root.join("b"), root.join("c")]);
+}
+
+
+
+// =================
+// === Hardlinks ===
+// =================
+
+#[test]
+fn test_hardlinks_are_hashed_once_and_marked() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    ["a", "b", "c", "d", "e", "f"].iter().for_each(|d| fs::create_dir_all(root.join(d)).unwrap());
+    fs::write(root.join("a/x"), "x").unwrap();
+    fs::write(root.join("a/y"), "y").unwrap();
+    fs::hard_link(root.join("a/x"), root.join("b/x")).unwrap();
+    fs::hard_link(root.join("a/y"), root.join("b/y")).unwrap();
+    fs::write(root.join("c/x"), "x").unwrap();
+    fs::write(root.join("c/y"), "y").unwrap();
+    fs::write(root.join("d/z"), "z").unwrap();
+    fs::hard_link(root.join("d/z"), root.join("e/z")).unwrap();
+
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let report = run_all(&mut state, &root, &Options::default());
+    assert_eq!(report.scan.stats, Stats { dropped: 0, kept: 0, refreshed: 8 });
+    assert_eq!(report.scan.hardlinks, 3);
+    let dup_dirs = report.dup_dirs.iter().map(|d| (d.path.clone(), d.hardlinked));
+    let dup_dirs = dup_dirs.collect::<Vec<_>>();
+    let expected = vec![
+        (root.join("a"), false),
+        (root.join("b"), true),
+        (root.join("c"), false),
+        (root.join("d"), true),
+        (root.join("e"), true),
+    ];
+    assert_eq!(dup_dirs, expected);
+
+    // Inodes hashed by an earlier scan aren't read again either.
+    fs::hard_link(root.join("d/z"), root.join("f/z")).unwrap();
+    let report = run_all(&mut state, &root, &Options::default());
+    assert_eq!(report.scan.stats, Stats { dropped: 0, kept: 8, refreshed: 1 });
+    assert_eq!(report.scan.hardlinks, 1);
+    let entries = state.entries();
+    assert_eq!(entries[&root.join("f/z")].hash, entries[&root.join("d/z")].hash);
+}
+
+
+
+// ==============
+// === Filter ===
+// ==============
+
+#[test]
+fn test_filter_excludes_includes_and_applies_ignore_files() {
+    let files = [
+        (".gitignore", "*.log\n!keep.log\n"),
+        (".git/HEAD", "ref"),
+        ("a/x.txt", "x"),
+        ("a/y.log", "y"),
+        ("a/keep.log", "keep"),
+        ("node_modules/pkg/index.js", "js"),
+        ("target/out", "out"),
+        ("sub/.ignore", "z.txt\n"),
+        ("sub/w.txt", "w"),
+        ("sub/z.txt", "z"),
+        ("sub/target/out", "out"),
+    ];
+    let (dir, root) = crate::write_tree(&files);
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let mut scan = |filter: Filter| {
+        let options = Options { filter, ..Default::default() };
+        let _ = run_all(&mut state, &root, &options);
+        let entries = state.entries().keys();
+        let entries = entries.map(|p| p.strip_prefix(&root).unwrap().to_str().unwrap().to_string());
+        entries.collect::<Vec<_>>()
+    };
+
+    assert_eq!(scan(Filter::default()).len(), files.len());
+
+    // Files excluded since the previous scan are dropped from the state.
+    let mut filter = Filter::default();
+    filter.exclude("node_modules/").unwrap();
+    filter.exclude("/target").unwrap();
+    filter.gitignore = true;
+    let expected = [
+        ".gitignore",
+        "a/keep.log",
+        "a/x.txt",
+        "sub/.ignore",
+        "sub/target/out",
+        "sub/w.txt",
+    ];
+    assert_eq!(scan(filter), expected);
+
+    let mut filter = Filter::default();
+    filter.include("*.txt").unwrap();
+    filter.exclude("z.txt").unwrap();
+    assert_eq!(scan(filter), ["a/x.txt", "sub/w.txt"]);
+
+    let mut filter = Filter::default();
+    assert!(matches!(filter.exclude("a[").unwrap_err(), Error::InvalidPattern(_)));
+}
+
+
+
+// =================
+// === JunkFiles ===
+// =================
+
+#[test]
+fn test_junk_files_are_scanned_but_ignored_for_directory_identity() {
+    let files = [
+        ("a/x", "x"),
+        ("a/.DS_Store", "mac"),
+        ("b/x", "x"),
+        ("b/Thumbs.db", "windows"),
+        ("b/Desktop.ini", "windows"),
+        ("c/y", "y"),
+        ("d/.DS_Store", "mac"),
+        ("e/.DS_Store", "mac"),
+    ];
+    let (dir, root) = crate::write_tree(&files);
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let dup_dirs = |report: Report| report.dup_dirs.into_iter().map(|d| d.path);
+    let dup_dirs = |report: Report| dup_dirs(report).collect::<Vec<_>>();
+
+    let report = run_all(&mut state, &root, &Options::default());
+    assert_eq!(state.entries().len(), files.len());
+    // Directories with nothing but junk are not duplicates of each other.
+    assert_eq!(dup_dirs(report), vec![root.join("a"), root.join("b")]);
+
+    let options = Options { junk_files: Vec::new(), ..Default::default() };
+    let report = run_all(&mut state, &root, &options);
+    assert_eq!(dup_dirs(report), vec![root.join("d"), root.join("e")]);
+}
+
+
+
+// ===================
+// === DirHashMode ===
+// ===================
+
+#[test]
+fn test_multiset_dir_hashes_count_copies() {
+    use DirHashMode::*;
+
+    let hash = dir_hash;
+    for mode in [Multiset, Set] {
+        // Names and order never matter.
+        let a = hash(mode, &[("x", "1"), ("y", "2")]);
+        assert_eq!(a, hash(mode, &[("b", "2"), ("a", "1")]));
+        assert_eq!(a, hash(mode, &[("sub/x", "1"), ("y", "2")]));
+        assert_ne!(a, hash(mode, &[("x", "1"), ("y", "3")]));
+        assert_ne!(a, hash(mode, &[("x", "1")]));
+    }
+
+    let one = [("x", "1")];
+    let two = [("x", "1"), ("y", "1")];
+    let three = [("x", "1"), ("y", "1"), ("z", "1")];
+    let nested = [("a/x", "1"), ("b/x", "1")];
+    assert_ne!(hash(Multiset, &one), hash(Multiset, &two));
+    assert_ne!(hash(Multiset, &one), hash(Multiset, &three));
+    assert_ne!(hash(Multiset, &two), hash(Multiset, &three));
+    assert_eq!(hash(Multiset, &two), hash(Multiset, &nested));
+    assert_eq!(hash(Set, &one), hash(Set, &two));
+    assert_eq!(hash(Set, &one), hash(Set, &three));
+    assert_eq!(hash(Set, &one), hash(Set, &nested));
+
+    let mixed = [("x", "1"), ("y", "1"), ("z", "2")];
+    let other = [("x", "1"), ("y", "2"), ("z", "2")];
+    assert_ne!(hash(Multiset, &mixed), hash(Multiset, &other));
+    assert_eq!(hash(Set, &mixed), hash(Set, &other));
+}
+
+#[test]
+fn test_path_aware_dir_hashes_compare_trees() {
+    use DirHashMode::*;
+
+    let hash = dir_hash;
+    let nested = [("a/x.jpg", "1"), ("b/y.jpg", "2")];
+    let flat = [("x.jpg", "1"), ("y.jpg", "2")];
+    assert_eq!(hash(Multiset, &nested), hash(Multiset, &flat));
+    for mode in [RelativePaths, Merkle] {
+        let tree = hash(mode, &nested);
+        assert_eq!(tree, hash(mode, &[("b/y.jpg", "2"), ("a/x.jpg", "1")]));
+        assert_ne!(tree, hash(mode, &flat));
+        assert_ne!(tree, hash(mode, &[("a/x.jpg", "1"), ("b/z.jpg", "2")]));
+        assert_ne!(tree, hash(mode, &[("a/x.jpg", "1"), ("c/y.jpg", "2")]));
+        assert_ne!(tree, hash(mode, &[("a/x.jpg", "2"), ("b/y.jpg", "1")]));
+        assert_ne!(tree, hash(mode, &[("a/x.jpg", "1"), ("a/b/y.jpg", "2")]));
+        assert_ne!(hash(mode, &[("a/b", "1")]), hash(mode, &[("a", "1"), ("b", "1")]));
+    }
+}
+
+#[test]
+fn test_dir_hashes_visit_each_directory_once() {
+    use DirHashMode::*;
+
+    let files = [
+        ("a/b/c/y", "y"),
+        ("a/b/x", "x"),
+        ("a/b-c/x", "x"),
+        ("a/b.d/x", "x"),
+        ("a/bx", "bx"),
+        ("e/x", "x"),
+    ];
+    let (dir, root) = crate::write_tree(&files);
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let _ = dupdir_core::scan(&mut state, &root, &Options::default()).unwrap();
+    for dir_hash_mode in [Multiset, Set, RelativePaths, Merkle] {
+        let options = Options { dir_hash_mode, ..Default::default() };
+        let dir_hashes = dupdir_core::dir_hashes(&state, &root, &options);
+        let dirs = dir_hashes.iter().map(|(_, d)| d.strip_prefix(&root).unwrap());
+        let mut dirs = dirs.map(|d| d.to_str().unwrap()).collect::<Vec<_>>();
+        dirs.sort();
+        assert_eq!(dirs, ["", "a", "a/b", "a/b-c", "a/b.d", "a/b/c", "e"]);
+        let hash = |d: &str| {
+            let mut dir_hashes = dir_hashes.iter();
+            dir_hashes.find(|(_, p)| *p == root.join(d)).unwrap().0.clone()
+        };
+        assert_eq!(hash("a/b-c"), hash("a/b.d"));
+        assert_eq!(hash("a/b-c"), hash("e"));
+        assert_ne!(hash("a/b"), hash("a/b-c"));
+    }
+}
+
+#[test]
+fn test_dir_hashes_stay_within_the_root() {
+    let (dir, root) = crate::write_tree(&[("a/x", "x"), ("b/x", "x"), ("c/y", "x")]);
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    for exclude_root in [false, true] {
+        let options = Options { exclude_root, ..Default::default() };
+        let report = run_all(&mut state, &root, &options);
+        let dir_hashes = dupdir_core::dir_hashes(&state, &root, &options);
+        assert!(dir_hashes.iter().all(|(_, d)| d.starts_with(&root)));
+        assert_eq!(dir_hashes.iter().any(|(_, d)| *d == root), !exclude_root);
+        assert_eq!(dir_hashes.len(), 3 + usize::from(!exclude_root));
+        let dup_dirs = report.dup_dirs.iter().map(|d| d.path.clone()).collect::<Vec<_>>();
+        assert_eq!(dup_dirs, [root.join("a"), root.join("b"), root.join("c")]);
+    }
+
+    // A root which is a single file has no directories.
+    let file = root.join("a/x");
+    let _ = dupdir_core::scan(&mut state, &file, &Options::default()).unwrap();
+    assert!(dupdir_core::dir_hashes(&state, &file, &Options::default()).is_empty());
+}
+
+/// Returns the hash of a new directory holding `files`, under `mode`.
+fn dir_hash(mode: DirHashMode, files: &[(&str, &str)]) -> String {
+    let (dir, root) = crate::write_tree(files);
+    // Each root has its own state, so files are hashed in full for their hashes to compare.
+    let options = Options { dir_hash_mode: mode, staged: false, ..Default::default() };
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+    let dir_hashes = dupdir_core::dir_hashes(&state, &root, &options);
+    let mut dir_hashes = dir_hashes.into_iter();
+    let (hash, _) = dir_hashes.find(|(_, d)| *d == root).unwrap();
+    hash
+}
+
+
+
+// ===============
+// === DupDirs ===
+// ===============
+
+#[test]
+fn test_dup_dirs_only_suppress_descendants() {
+    let dir_hashes = [
+        ("h1", "/data/foo"),
+        ("h1", "/data/foobar"),
+        ("h1", "/data/foo/sub"),
+        ("h1", "/data/foo-bar"),
+        ("h2", "/data/a"),
+        ("h2", "/data/ab"),
+        ("h2", "/data/a/b/c"),
+        ("h3", "/data/x"),
+        ("h3", "/data/x/x"),
+        ("h4", "/data/unique"),
+    ];
+    let dir_hashes = dir_hashes.iter().map(|(h, d)| (h.to_string(), Path::new(d)));
+    let dir_hashes = dir_hashes.collect::<Vec<_>>();
+    let dup_dirs = dupdir_core::dup_dirs(&dir_hashes);
+    let dup_dirs = dup_dirs.iter().map(|d| (d.hash.as_str(), d.path.to_str().unwrap()));
+    let dup_dirs = dup_dirs.collect::<Vec<_>>();
+    let expected = [
+        ("h2", "/data/a"),
+        ("h2", "/data/ab"),
+        ("h1", "/data/foo"),
+        ("h1", "/data/foo-bar"),
+        ("h1", "/data/foobar"),
+    ];
+    assert_eq!(dup_dirs, expected);
+}
+
+#[test]
+fn test_maximal_dup_dirs_drop_groups_nested_in_other_groups() {
+    let files = ["a/x/y/f", "a/g", "b/x/y/f", "b/g", "c/y/f", "c/h", "d/e/f"];
+    let files = files.map(|f| (f, "x"));
+    let (dir, root) = crate::write_tree(&files);
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let mut dup_dirs = |expanded| {
+        let dir_hash_mode = DirHashMode::RelativePaths;
+        let options = Options { expanded, dir_hash_mode, ..Default::default() };
+        let report = run_all(&mut state, &root, &options);
+        let dup_dirs = report.dup_dirs.iter().map(|d| d.path.strip_prefix(&root).unwrap());
+        dup_dirs.map(|d| d.to_str().unwrap().to_string()).collect::<Vec<_>>()
+    };
+
+    // `a/x` and `b/x` lie inside `a` and `b`, while `c/y` and `d/e` keep the group of `a/x/y`.
+    let expanded = ["a", "a/x", "a/x/y", "b", "b/x", "b/x/y", "c/y", "d/e"];
+    assert_eq!(dup_dirs(true), expanded);
+    assert_eq!(dup_dirs(false), ["a", "a/x/y", "b", "b/x/y", "c/y", "d/e"]);
+}
+
+#[test]
+fn test_maximal_dup_dirs_keep_groups_spread_over_several_groups() {
+    let dup_dirs = [
+        ("h1", "/a"),
+        ("h3", "/a/x"),
+        ("h4", "/a/z"),
+        ("h1", "/b"),
+        ("h3", "/b/x"),
+        ("h4", "/b/z"),
+        ("h2", "/c"),
+        ("h3", "/c/y"),
+        ("h2", "/d"),
+        ("h3", "/d/y"),
+    ];
+    let dup_dirs = dup_dirs.iter().map(|(h, d)| {
+        let hash = h.to_string();
+        DupDir { hash, path: Path::new(d).to_path_buf(), hardlinked: false }
+    });
+    let dup_dirs = dupdir_core::maximal_dup_dirs(dup_dirs.collect());
+    let dup_dirs = dup_dirs.iter().map(|d| d.path.to_str().unwrap()).collect::<Vec<_>>();
+    // No single group holds all of `h3`, so only `h4`, inside `/a` and `/b`, is dropped.
+    let expected = ["/a", "/a/x", "/b", "/b/x", "/c", "/c/y", "/d", "/d/y"];
+    assert_eq!(dup_dirs, expected);
+}
+
+
+
+// ==============
+// === Stages ===
+// ==============
+
+#[test]
+fn test_files_are_only_read_as_far_as_needed() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    fs::create_dir_all(&root).unwrap();
+    let write = |name: &str, contents: &[u8]| fs::write(root.join(name), contents).unwrap();
+    let middle = |byte| [vec![0; 5000], vec![byte; 100], vec![0; 5000]].concat();
+    write("unique", b"12345");
+    write("sample_a", &[b"a".repeat(9000)].concat());
+    write("sample_b", &[b"b".repeat(9000)].concat());
+    write("middle_a", &middle(1));
+    write("middle_b", &middle(2));
+    write("copy_a", b"xyz");
+    write("copy_b", b"xyz");
+
+    let state_path = dir.path().join("state.json");
+    let mut state = State::load(&state_path).unwrap();
+    let _ = dupdir_core::scan(&mut state, &root, &Options::default()).unwrap();
+    let stages = |state: &State| {
+        let entries = state.entries().iter();
+        let entries = entries.map(|(p, e)| (p.file_name().unwrap().to_str().unwrap(), e.stage));
+        entries.map(|(p, s)| (p.to_string(), s)).collect::<BTreeMap<_, _>>()
+    };
+    let hash = |state: &State, name: &str| state.entries()[&root.join(name)].hash.clone();
+    let expected = [
+        ("copy_a", Stage::Full),
+        ("copy_b", Stage::Full),
+        ("middle_a", Stage::Full),
+        ("middle_b", Stage::Full),
+        ("sample_a", Stage::Sample),
+        ("sample_b", Stage::Sample),
+        ("unique", Stage::Size),
+    ];
+    let expected = expected.map(|(p, s)| (p.to_string(), s)).into_iter().collect();
+    assert_eq!(stages(&state), expected);
+    assert_eq!(hash(&state, "copy_a"), hash(&state, "copy_b"));
+    assert_ne!(hash(&state, "middle_a"), hash(&state, "middle_b"));
+    assert_ne!(hash(&state, "sample_a"), hash(&state, "sample_b"));
+
+    // Stages are saved, and a file whose size stops being unique is sampled.
+    write("unique_too", b"54321");
+    let mut state = State::load(&state_path).unwrap();
+    let report = dupdir_core::scan(&mut state, &root, &Options::default()).unwrap();
+    assert_eq!(report.stats, Stats { dropped: 0, kept: 7, refreshed: 1 });
+    assert_eq!(stages(&state)["unique"], Stage::Sample);
+    assert_eq!(stages(&state)["unique_too"], Stage::Sample);
+    assert_ne!(hash(&state, "unique"), hash(&state, "unique_too"));
+
+    // Without stages, every file is read in full.
+    let options = Options { staged: false, ..Default::default() };
+    let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+    assert!(stages(&state).values().all(|s| *s == Stage::Full));
+    assert_eq!(hash(&state, "unique"), hash_of(&dir, "12345"));
+}
+
+
+
+// ================
+// === DupFiles ===
+// ================
+
+#[test]
+fn test_dup_files_report_size_wasted_bytes_and_hardlinks() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    ["a", "b", "c"].iter().for_each(|d| fs::create_dir_all(root.join(d)).unwrap());
+    fs::write(root.join("a/x"), "xyz").unwrap();
+    fs::write(root.join("b/x"), "xyz").unwrap();
+    fs::write(root.join("b/y"), "xyz").unwrap();
+    fs::hard_link(root.join("a/x"), root.join("c/x")).unwrap();
+    fs::write(root.join("c/z"), "z").unwrap();
+    ["a", "b"].iter().for_each(|d| fs::write(root.join(d).join("empty"), "").unwrap());
+    ["a", "b"].iter().for_each(|d| fs::write(root.join(d).join(".DS_Store"), "junk").unwrap());
+
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let options = Options::default();
+    let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+    let dup_files = dupdir_core::dup_files(&state, &root, &Verified::default(), &options);
+    let dup_files = dup_files.iter().map(|f| (f.path.clone(), f.size, f.wasted, f.hardlinked));
+    let dup_files = dup_files.collect::<Vec<_>>();
+    let expected = vec![
+        (root.join("a/x"), 3, 6, false),
+        (root.join("b/x"), 3, 6, false),
+        (root.join("b/y"), 3, 6, false),
+        (root.join("c/x"), 3, 6, true),
+    ];
+    assert_eq!(dup_files, expected);
+}
+
+
+
+// ==============
+// === Verify ===
+// ==============
+
+#[test]
+fn test_verify_splits_groups_whose_hashes_collide() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    ["a", "b", "c"].iter().for_each(|d| {
+        fs::create_dir_all(root.join(d)).unwrap();
+        fs::write(root.join(d).join("x"), if *d == "c" { "xyz" } else { "abc" }).unwrap();
+        fs::write(root.join(d).join("y"), "same").unwrap();
+    });
+    let state_path = dir.path().join("state.json");
+    let mut state = State::load(&state_path).unwrap();
+    let options = Options { staged: false, ..Default::default() };
+    let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+    // Giving `c/x` the hash of `a/x` in the state makes them collide, as cached hashes are kept
+    // while the files don't change.
+    let path = root.join("c/x");
+    let hash = |name: &str| state.entries()[&root.join(name)].hash.clone();
+    let json = fs::read_to_string(&state_path).unwrap();
+    fs::write(&state_path, json.replace(&hash("c/x"), &hash("a/x"))).unwrap();
+    let mut state = State::load(&state_path).unwrap();
+    let paths = |paths: Vec<&Path>| paths.iter().map(|p| root.join(p)).collect::<Vec<_>>();
+
+    let report = run_all(&mut state, &root, &Options::default());
+    let dup_dirs = report.dup_dirs.into_iter().map(|d| d.path).collect::<Vec<_>>();
+    assert_eq!(dup_dirs, paths(vec!["a".as_ref(), "b".as_ref(), "c".as_ref()]));
+
+    let options = Options { verify: true, ..Default::default() };
+    let report = run_all(&mut state, &root, &options);
+    assert_eq!(report.collisions, 2);
+    let dup_dirs = report.dup_dirs.into_iter().map(|d| d.path).collect::<Vec<_>>();
+    assert_eq!(dup_dirs, paths(vec!["a".as_ref(), "b".as_ref()]));
+
+    let verified = dupdir_core::verify_files(&state, &root, &options).unwrap();
+    assert_eq!(verified.collisions, 1);
+    let entry = &state.entries()[&path];
+    assert_eq!(verified.hash(&path, entry), format!("{}-1", entry.hash));
+    let dup_files = dupdir_core::dup_files(&state, &root, &verified, &options);
+    let dup_files = dup_files.iter().map(|f| f.path.strip_prefix(&root).unwrap());
+    let expected = ["a/x", "a/y", "b/x", "b/y", "c/y"].map(Path::new);
+    assert_eq!(dup_files.collect::<Vec<_>>(), expected);
+}
+
+
+
+// ===================
+// === SimilarDirs ===
+// ===================
+
+#[test]
+fn test_similar_dirs_report_shared_and_own_contents() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    let write = |path: &str, contents: &str| {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    };
+    (0..20).for_each(|i| write(&format!("photos/{i}.jpg"), &format!("photo {i}")));
+    (0..19).for_each(|i| write(&format!("backup/photos/{i}.jpg"), &format!("photo {i}")));
+    write("backup/photos/19.jpg", "edited");
+    write("photos/.DS_Store", "junk");
+    write("docs/a.txt", "a");
+    // `more` holds the same contents as `more/b`, but isn't a mere wrapper around it.
+    (0..10).for_each(|i| write(&format!("more/a/{i}.txt"), &format!("doc {i}")));
+    (0..11).for_each(|i| write(&format!("more/b/{i}.txt"), &format!("doc {i}")));
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let mut similar_dirs = |similarity, min_similarity| {
+        let options = Options { similarity, min_similarity, ..Default::default() };
+        let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+        dupdir_core::similar_dirs(&state, &root, &options)
+    };
+
+    // `backup` stands for `backup/photos`, and neither is compared with `root`, which holds both.
+    let similar = similar_dirs(Similarity::Jaccard, 0.9);
+    let pairs = similar.iter().map(|d| (d.left.strip_prefix(&root).unwrap(), d.right.as_path()));
+    let pairs = pairs.map(|(l, r)| (l.to_str().unwrap(), r.strip_prefix(&root).unwrap()));
+    let pairs = pairs.map(|(l, r)| (l, r.to_str().unwrap())).collect::<Vec<_>>();
+    assert_eq!(pairs, [("more/a", "more/b"), ("backup", "photos")]);
+    let similar = &similar[1];
+    assert_eq!((&similar.left, &similar.right), (&root.join("backup"), &root.join("photos")));
+    assert!((similar.similarity - 19.0 / 21.0).abs() < 1e-9);
+    assert_eq!((similar.shared.files, similar.shared.bytes), (19, 10 * 7 + 9 * 8));
+    assert_eq!((similar.left_only.files, similar.left_only.bytes), (1, 6));
+    assert_eq!((similar.right_only.files, similar.right_only.bytes), (1, 8));
+
+    // 19 of the 20 photos are in both directories.
+    assert!(similar_dirs(Similarity::Jaccard, 0.95).is_empty());
+    let similar = similar_dirs(Similarity::Containment, 0.95);
+    let similarities = similar.iter().map(|d| (d.left.clone(), d.similarity));
+    let similarities = similarities.collect::<Vec<_>>();
+    assert_eq!(similarities, [(root.join("more/a"), 1.0), (root.join("backup"), 0.95)]);
+}
+
+#[test]
+fn test_similar_dirs_dont_share_empty_files() {
+    let files = [("a/empty", ""), ("b/empty", ""), ("a/x", "x"), ("b/y", "y")];
+    let (dir, root) = crate::write_tree(&files);
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let options = Options { min_similarity: 0.3, ..Default::default() };
+    let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+    assert!(dupdir_core::similar_dirs(&state, &root, &options).is_empty());
+}
+
+#[test]
+fn test_similar_dirs_index_contents_held_by_many_nested_dirs() {
+    let files = (0..60).flat_map(|i| {
+        let own = (format!("{i}/own"), format!("own {i}"));
+        let shared = (format!("{i}/q/x"), "x".to_string());
+        let unique = (format!("{i}/q/u"), format!("u {i}"));
+        [own, shared, unique]
+    });
+    let files = files.collect::<Vec<_>>();
+    let files = files.iter().map(|(f, contents)| (f.as_str(), contents.as_str()));
+    let (dir, root) = crate::write_tree(&files.collect::<Vec<_>>());
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let options = Options { min_similarity: 0.3, ..Default::default() };
+    let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+    // `x` is under 121 directories, but only the 60 `q` directories are the deepest to hold it.
+    let similar = dupdir_core::similar_dirs(&state, &root, &options);
+    assert_eq!(similar.len(), 60 * 59 / 2);
+    assert!(similar.iter().all(|d| d.left.ends_with("q") && d.right.ends_with("q")));
+}
+
+#[test]
+fn test_contained_dirs_are_found_in_one_or_two_other_dirs() {
+    let dir = TempDir::new("dupdir").unwrap();
+    let root = dir.path().join("root");
+    let files = [
+        ("backup", "12"),
+        ("copy", "12"),
+        ("docs", "4"),
+        ("nearly", "1235u"),
+        ("old", "1234"),
+        ("photos", "1235"),
+    ];
+    files.iter().for_each(|(dir, contents)| {
+        let dir = root.join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        contents.chars().for_each(|c| fs::write(dir.join(c.to_string()), [c as u8]).unwrap());
+    });
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let mut contained_dirs = |min_similarity| {
+        let options = Options { min_similarity, ..Default::default() };
+        let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+        let contained = dupdir_core::contained_dirs(&state, &root, &options);
+        let relative = |p: &Path| p.strip_prefix(&root).unwrap().to_str().unwrap().to_string();
+        let contained = contained.iter().map(|c| {
+            let containers = c.containers.iter().map(|p| relative(p)).collect::<Vec<_>>();
+            let missing = c.missing.iter().map(|p| relative(p)).collect::<Vec<_>>();
+            (relative(&c.path), containers, missing)
+        });
+        contained.collect::<Vec<_>>()
+    };
+
+    // `backup` is kept as the first of two copies, and `old` is only found in `photos` and `docs`
+    // together.
+    let contained = contained_dirs(0.8);
+    let expected = [
+        ("backup", vec!["old"], vec![]),
+        ("copy", vec!["backup"], vec![]),
+        ("docs", vec!["old"], vec![]),
+        ("nearly", vec!["photos"], vec!["nearly/u"]),
+        ("old", vec!["photos", "docs"], vec![]),
+        ("photos", vec!["nearly"], vec![]),
+    ];
+    let expected = expected.map(|(dir, containers, missing)| {
+        let strings = |v: Vec<&str>| v.into_iter().map(str::to_string).collect::<Vec<_>>();
+        (dir.to_string(), strings(containers), strings(missing))
+    });
+    assert_eq!(contained, expected);
+    let contained = contained_dirs(0.9);
+    assert!(contained.iter().all(|(dir, _, missing)| dir != "nearly" && missing.is_empty()));
+}
+
+#[test]
+fn test_dirs_of_empty_files_are_not_contained() {
+    let (dir, root) = crate::write_tree(&[("a/empty", ""), ("a/x", "x"), ("b/empty", "")]);
+    let mut state = State::load(dir.path().join("state.json")).unwrap();
+    let options = Options::default();
+    let _ = dupdir_core::scan(&mut state, &root, &options).unwrap();
+    assert!(dupdir_core::contained_dirs(&state, &root, &options).is_empty());
+}